@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::request::Request;
+use crate::response::Status;
+
+/// What a [JoinAuthorizer] found out about a client it let join a room.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthInfo {
+    /// The user the client authenticated as, if the authorizer knows users.
+    pub user_id: Option<String>,
+}
+
+/// Why a [JoinAuthorizer] refused a client, sent back as the response to the
+/// upgrade request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthError {
+    pub status: Status,
+    pub message: String,
+}
+
+/// Decides who may join a room, e.g. by checking a token or a session
+/// cookie of the upgrade request.
+pub trait JoinAuthorizer: Send + Sync {
+    /// Authorizes the websocket upgrade `request` to join `room`. Called
+    /// before the handshake is answered, so a rejected client never gets a
+    /// websocket.
+    fn authorize<'a>(
+        &'a self,
+        request: &'a Request,
+        room: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<AuthInfo, AuthError>> + Send + 'a>>;
+}
+
+/// Lets everyone join every room, without knowing who they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl JoinAuthorizer for AllowAll {
+    fn authorize<'a>(
+        &'a self,
+        _request: &'a Request,
+        _room: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<AuthInfo, AuthError>> + Send + 'a>> {
+        Box::pin(async { Ok(AuthInfo::default()) })
+    }
+}
+
+impl AuthError {
+    pub fn new<M: Into<String>>(status: Status, message: M) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
@@ -0,0 +1,653 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use backend::request::Limits;
+use serde::Deserialize;
+
+/// Name of the room that is created on startup by default.
+pub const DEFAULT_ROOM_NAME: &str = "roomForAll";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the server binds to.
+    pub host: String,
+    pub port: u16,
+    /// Rooms that can exist at the same time, including the default room.
+    pub max_rooms: usize,
+    /// Members a new room admits. `None` admits everyone.
+    pub max_room_members: Option<usize>,
+    /// Time between Pings sent to every member, so connections stay open
+    /// through proxies and dead peers are noticed. `None` sends no Pings.
+    pub ping_interval: Option<Duration>,
+    /// Size in bytes of the largest message a member may send.
+    pub max_message_size: usize,
+    /// Characters of the longest text message relayed to a room. Longer ones
+    /// are answered with an error to the sender only. `None` relays any
+    /// message up to [Config::max_message_size].
+    pub max_chat_len: Option<usize>,
+    /// Messages per second a single connection may send on average.
+    pub message_rate: f64,
+    /// Messages a single connection may send in a burst.
+    pub message_burst: u32,
+    /// Number of rate limited messages in a row after which a connection is
+    /// closed.
+    /// `None` only drops the messages.
+    pub max_rate_violations: Option<u32>,
+    /// Number of relay cycles in a row a member's send queue may stay full
+    /// before the member is removed. `None` keeps slow members, they only miss
+    /// messages.
+    pub max_full_send_cycles: Option<u32>,
+    /// Time between relay cycles of a room while its members send messages.
+    pub relay_interval: Duration,
+    /// Longest time an idle room's relay task sleeps. Messages wake it right
+    /// away, so this only matters for housekeeping like removing the room.
+    pub relay_idle_interval: Duration,
+    /// Time between checks for empty rooms to remove and reconnection tokens
+    /// that expired.
+    pub cleanup_interval: Duration,
+    /// What happens to a message for a member whose send queue is full.
+    pub send_policy: SendPolicy,
+    /// Time after which a websocket whose client hasn't sent anything, not
+    /// even a heartbeat, is closed. `None` keeps silent clients connected.
+    pub idle_timeout: Option<Duration>,
+    /// Time writing a single message to a client may take, e.g. while it
+    /// doesn't read and the socket buffer is full. Also limits the handshake
+    /// response and welcome message. `None` waits as long as it takes.
+    pub write_timeout: Option<Duration>,
+    /// Time a member that left can rejoin its room with the reconnection
+    /// token it got on joining, keeping its id and nick.
+    pub reconnect_grace: Duration,
+    /// Time after which an empty room is removed, even if nobody ever joined it.
+    pub room_ttl: Duration,
+    /// Whether rooms send their member list to all members whenever someone
+    /// joins or leaves.
+    pub broadcast_members: bool,
+    /// Whether rooms send a join or leave event to all members whenever
+    /// someone joins or leaves.
+    pub member_events: bool,
+    /// Name of the permanent room created on startup. `None` starts without
+    /// any room, so only generated rooms exist.
+    pub default_room: Option<String>,
+    /// Number of recent messages a room keeps to replay to new members.
+    pub history_size: usize,
+    /// Connections served at the same time, including open websockets.
+    /// Further connections are answered with 503.
+    pub max_connections: usize,
+    /// Connections a single address may have open at the same time. Further
+    /// connections are answered with 429.
+    pub max_connections_per_ip: usize,
+    /// Whether the leftmost `X-Forwarded-For` entry is taken as the client
+    /// address. Only enable this behind a proxy that sets the header, clients
+    /// can send anything in it otherwise.
+    pub trust_proxy: bool,
+    /// Time a client has to send a complete request.
+    pub request_timeout: Duration,
+    /// Time an idle keep-alive connection is held open for the next request.
+    pub keep_alive_timeout: Duration,
+    pub request_limits: Limits,
+    /// Websocket subprotocols the server speaks, in order of preference.
+    pub subprotocols: Vec<String>,
+    /// Origins allowed to open websockets, e.g. `https://example.com`.
+    /// Handshakes from other origins or without an `Origin` header are
+    /// answered with 403. Empty allows every origin.
+    pub allowed_origins: Vec<String>,
+    /// PEM files with the certificate chain and private key. If set, the
+    /// server only speaks TLS, i.e. `https://` and `wss://`.
+    pub tls: Option<TlsFiles>,
+    pub log_format: LogFormat,
+    /// Log filter used if `RUST_LOG` isn't set, e.g. `info` or
+    /// `backend=debug`.
+    pub log_level: String,
+    /// Bearer token required by the `/api/admin` endpoints. `None` disables
+    /// them. Only read from the environment and the config file, so it
+    /// doesn't show up in the process list.
+    pub admin_token: Option<String>,
+}
+
+/// Settings read from the config file given with `--config` or
+/// `CONFIG_FILE`. Missing settings keep their default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    host: Option<String>,
+    port: Option<u16>,
+    max_rooms: Option<usize>,
+    max_room_members: Option<usize>,
+    ping_interval_secs: Option<u64>,
+    max_message_size: Option<usize>,
+    log_level: Option<String>,
+    admin_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How messages are relayed to a member that doesn't keep up with reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendPolicy {
+    /// Wait until the member's send queue has room again. This holds up the
+    /// whole room.
+    Block,
+    /// Skip the new message for that member.
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued message to make room for the new one, so the
+    /// member stays roughly live.
+    DropOldest,
+    /// Remove the member right away.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable output.
+    #[default]
+    Pretty,
+    /// One json object per line, for log aggregators.
+    Json,
+}
+
+impl Config {
+    /// Builds the config from the config file, the environment variables and
+    /// the command line arguments, excluding the program name. Arguments take
+    /// precedence over environment variables, which take precedence over the
+    /// file. Without a file, the defaults are used.
+    pub fn from_env_and_args<V, A>(vars: V, args: A) -> anyhow::Result<Self>
+    where
+        V: IntoIterator<Item = (String, String)>,
+        A: IntoIterator<Item = String>,
+    {
+        let vars = vars.into_iter().collect::<Vec<_>>();
+        let args = args.into_iter().collect::<Vec<_>>();
+        let mut config = Self::default();
+        if let Some(path) = config_file_path(&vars, &args) {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read config file {}", path))?;
+            config
+                .apply_file(&contents)
+                .with_context(|| format!("invalid config file {}", path))?;
+        }
+        for (name, value) in vars {
+            match name.as_str() {
+                "HOST" => config.host = value,
+                "PORT" => config.port = parse_port(&value)?,
+                "ADMIN_TOKEN" => config.admin_token = Some(value),
+                _ => {}
+            }
+        }
+
+        let (mut tls_cert, mut tls_key) = (None, None);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // the only argument without a value
+            if arg == "--no-default-room" {
+                config.default_room = None;
+                continue;
+            }
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("missing value for argument {}", arg))?;
+                    (arg, value)
+                }
+            };
+            match name.as_str() {
+                // already read, see config_file_path
+                "--config" => {}
+                "--host" => config.host = value,
+                "--port" => config.port = parse_port(&value)?,
+                "--max-rooms" => config.max_rooms = parse_number(&name, &value)?,
+                "--max-room-members" => {
+                    config.max_room_members = Some(parse_number(&name, &value)?)
+                }
+                "--ping-interval" => {
+                    config.ping_interval = Some(parse_ping_interval(parse_number(&name, &value)?)?)
+                }
+                "--max-message-size" => config.max_message_size = parse_number(&name, &value)?,
+                "--max-chat-len" => config.max_chat_len = Some(parse_number(&name, &value)?),
+                "--relay-interval" => {
+                    config.relay_interval = Duration::from_millis(parse_number(&name, &value)?)
+                }
+                "--relay-idle-interval" => {
+                    config.relay_idle_interval = Duration::from_millis(parse_number(&name, &value)?)
+                }
+                "--cleanup-interval" => {
+                    config.cleanup_interval = Duration::from_millis(parse_number(&name, &value)?)
+                }
+                "--log-level" => config.log_level = value,
+                "--log-format" => config.log_format = value.parse()?,
+                "--send-policy" => config.send_policy = value.parse()?,
+                "--default-room" => config.default_room = Some(value),
+                "--allowed-origin" => config.allowed_origins.push(value),
+                "--tls-cert" => tls_cert = Some(PathBuf::from(value)),
+                "--tls-key" => tls_key = Some(PathBuf::from(value)),
+                "--trust-proxy" => {
+                    config.trust_proxy = value.parse().with_context(|| {
+                        format!(
+                            "invalid value {} for --trust-proxy, expected true or false",
+                            value
+                        )
+                    })?
+                }
+                _ => bail!("unknown argument {}", name),
+            }
+        }
+        config.tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (None, None) => None,
+            _ => bail!("--tls-cert and --tls-key have to be given together"),
+        };
+        Ok(config)
+    }
+
+    /// Overrides the settings found in the JSON config file `contents`.
+    fn apply_file(&mut self, contents: &str) -> anyhow::Result<()> {
+        let file: ConfigFile = serde_json::from_str(contents)?;
+        if let Some(host) = file.host {
+            self.host = host;
+        }
+        if let Some(port) = file.port {
+            if port == 0 {
+                bail!("invalid port 0, expected a number from 1 to 65535");
+            }
+            self.port = port;
+        }
+        if let Some(max_rooms) = file.max_rooms {
+            self.max_rooms = max_rooms;
+        }
+        if let Some(max_room_members) = file.max_room_members {
+            self.max_room_members = Some(max_room_members);
+        }
+        if let Some(secs) = file.ping_interval_secs {
+            self.ping_interval = Some(parse_ping_interval(secs)?);
+        }
+        if let Some(max_message_size) = file.max_message_size {
+            self.max_message_size = max_message_size;
+        }
+        if let Some(log_level) = file.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(admin_token) = file.admin_token {
+            self.admin_token = Some(admin_token);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the config file given with `--config`, or else with
+/// the `CONFIG_FILE` environment variable.
+fn config_file_path(vars: &[(String, String)], args: &[String]) -> Option<String> {
+    let arg = args.iter().enumerate().find_map(|(idx, arg)| {
+        if arg == "--config" {
+            args.get(idx + 1).cloned()
+        } else {
+            arg.strip_prefix("--config=").map(String::from)
+        }
+    });
+    arg.or_else(|| {
+        vars.iter()
+            .find(|(name, _)| name == "CONFIG_FILE")
+            .map(|(_, value)| value.clone())
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid value {} for {}, expected a number", value, name))
+}
+
+/// A zero interval would make the ping task send Pings in a busy loop.
+fn parse_ping_interval(secs: u64) -> anyhow::Result<Duration> {
+    if secs == 0 {
+        bail!("invalid ping interval 0, expected at least 1 second");
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_port(value: &str) -> anyhow::Result<u16> {
+    let port = value
+        .parse()
+        .with_context(|| format!("invalid port {}, expected a number from 1 to 65535", value))?;
+    if port == 0 {
+        bail!("invalid port 0, expected a number from 1 to 65535");
+    }
+    Ok(port)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: String::from("0.0.0.0"),
+            port: 8080,
+            max_rooms: 20,
+            max_room_members: None,
+            ping_interval: None,
+            max_message_size: 1 << 20,
+            max_chat_len: Some(1024),
+            message_rate: 10.0,
+            message_burst: 20,
+            max_rate_violations: Some(100),
+            // about 10 seconds at the default relay interval
+            max_full_send_cycles: Some(1200),
+            relay_interval: Duration::from_millis(8),
+            relay_idle_interval: Duration::from_secs(1),
+            cleanup_interval: Duration::from_secs(1),
+            send_policy: SendPolicy::default(),
+            idle_timeout: None,
+            write_timeout: Some(Duration::from_secs(10)),
+            reconnect_grace: Duration::from_secs(30),
+            room_ttl: Duration::from_secs(5 * 60),
+            default_room: Some(String::from(DEFAULT_ROOM_NAME)),
+            history_size: 50,
+            broadcast_members: false,
+            member_events: false,
+            max_connections: 1024,
+            max_connections_per_ip: 32,
+            trust_proxy: false,
+            request_timeout: Duration::from_secs(5),
+            keep_alive_timeout: Duration::from_secs(15),
+            request_limits: Limits::default(),
+            subprotocols: vec![String::from("morse")],
+            allowed_origins: Vec::new(),
+            tls: None,
+            log_format: LogFormat::default(),
+            log_level: String::from("trace"),
+            admin_token: None,
+        }
+    }
+}
+
+impl std::str::FromStr for SendPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-newest" => Ok(Self::DropNewest),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            other => bail!(
+                "unknown send policy {}, expected block, drop-newest, drop-oldest or disconnect",
+                other
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            other => bail!("unknown log format {}, expected pretty or json", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.log_format, LogFormat::Pretty);
+    }
+
+    #[test]
+    fn test_bind_address() {
+        let config =
+            Config::from_env_and_args(vars(&[("HOST", "127.0.0.1"), ("PORT", "3000")]), args(&[]))
+                .unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.port, 3000);
+
+        let config = Config::from_env_and_args(
+            vars(&[("PORT", "3000")]),
+            args(&["--host", "localhost", "--port=4000"]),
+        )
+        .unwrap();
+        assert_eq!(config.host, "localhost");
+        assert_eq!(config.port, 4000);
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.admin_token, None);
+        let config =
+            Config::from_env_and_args(vars(&[("ADMIN_TOKEN", "secret")]), args(&[])).unwrap();
+        assert_eq!(config.admin_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_invalid_port() {
+        for port in ["0", "65536", "-1", "http"] {
+            assert!(Config::from_env_and_args(vars(&[]), args(&["--port", port])).is_err());
+            assert!(Config::from_env_and_args(vars(&[("PORT", port)]), args(&[])).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ping_interval_arg() {
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--ping-interval", "30"])).unwrap();
+        assert_eq!(config.ping_interval, Some(Duration::from_secs(30)));
+        assert!(Config::from_env_and_args(vars(&[]), args(&["--ping-interval=0"])).is_err());
+    }
+
+    #[test]
+    fn test_log_format_arg() {
+        let config = Config::from_env_and_args(vars(&[]), args(&["--log-format", "json"])).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+        let config = Config::from_env_and_args(vars(&[]), args(&["--log-format=pretty"])).unwrap();
+        assert_eq!(config.log_format, LogFormat::Pretty);
+    }
+
+    #[test]
+    fn test_send_policy_arg() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.send_policy, SendPolicy::DropNewest);
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--send-policy", "drop-oldest"])).unwrap();
+        assert_eq!(config.send_policy, SendPolicy::DropOldest);
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--send-policy=disconnect"])).unwrap();
+        assert_eq!(config.send_policy, SendPolicy::Disconnect);
+        assert!(Config::from_env_and_args(vars(&[]), args(&["--send-policy=drop"])).is_err());
+    }
+
+    #[test]
+    fn test_trust_proxy_arg() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert!(!config.trust_proxy);
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--trust-proxy", "true"])).unwrap();
+        assert!(config.trust_proxy);
+        assert!(Config::from_env_and_args(vars(&[]), args(&["--trust-proxy=yes"])).is_err());
+    }
+
+    #[test]
+    fn test_allowed_origin_args() {
+        let config = Config::from_env_and_args(
+            vars(&[]),
+            args(&[
+                "--allowed-origin",
+                "https://a.example",
+                "--allowed-origin=https://b.example",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.allowed_origins,
+            ["https://a.example", "https://b.example"]
+        );
+    }
+
+    #[test]
+    fn test_tls_args() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.tls, None);
+        let config = Config::from_env_and_args(
+            vars(&[]),
+            args(&["--tls-cert", "cert.pem", "--tls-key=key.pem"]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.tls,
+            Some(TlsFiles {
+                cert: PathBuf::from("cert.pem"),
+                key: PathBuf::from("key.pem"),
+            })
+        );
+        assert!(Config::from_env_and_args(vars(&[]), args(&["--tls-cert", "cert.pem"])).is_err());
+    }
+
+    #[test]
+    fn test_default_room_args() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.default_room.as_deref(), Some(DEFAULT_ROOM_NAME));
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--default-room", "lobby"])).unwrap();
+        assert_eq!(config.default_room.as_deref(), Some("lobby"));
+        let config = Config::from_env_and_args(vars(&[]), args(&["--no-default-room"])).unwrap();
+        assert_eq!(config.default_room, None);
+    }
+
+    #[test]
+    fn test_config_file() {
+        let mut config = Config::default();
+        config
+            .apply_file(
+                r#"{
+                    "host": "127.0.0.1",
+                    "port": 9000,
+                    "max_rooms": 5,
+                    "max_room_members": 8,
+                    "ping_interval_secs": 20,
+                    "max_message_size": 4096,
+                    "log_level": "info"
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.max_rooms, 5);
+        assert_eq!(config.max_room_members, Some(8));
+        assert_eq!(config.ping_interval, Some(Duration::from_secs(20)));
+        assert_eq!(config.max_message_size, 4096);
+        assert_eq!(config.log_level, "info");
+
+        let mut config = Config::default();
+        config.apply_file(r#"{ "max_rooms": 3 }"#).unwrap();
+        assert_eq!(config.max_rooms, 3);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.ping_interval, None);
+
+        for invalid in [
+            r#"{ "max_room": 3 }"#,
+            r#"{ "port": 0 }"#,
+            r#"{ "ping_interval_secs": 0 }"#,
+            "max_rooms = 3",
+        ] {
+            assert!(
+                Config::default().apply_file(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_file_overridden_by_env_and_args() {
+        let path =
+            std::env::temp_dir().join(format!("morse-chat-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{ "host": "10.0.0.1", "port": 9000, "max_rooms": 5 }"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config =
+            Config::from_env_and_args(vars(&[("CONFIG_FILE", path), ("PORT", "3000")]), args(&[]));
+        let config = config.unwrap();
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.max_rooms, 5);
+
+        let config = Config::from_env_and_args(
+            vars(&[]),
+            args(&["--max-rooms=7", "--config", path, "--log-level", "warn"]),
+        );
+        std::fs::remove_file(path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.max_rooms, 7);
+        assert_eq!(config.log_level, "warn");
+
+        assert!(Config::from_env_and_args(vars(&[]), args(&["--config", path])).is_err());
+    }
+
+    #[test]
+    fn test_max_chat_len_arg() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.max_chat_len, Some(1024));
+        let config = Config::from_env_and_args(vars(&[]), args(&["--max-chat-len=80"])).unwrap();
+        assert_eq!(config.max_chat_len, Some(80));
+    }
+
+    #[test]
+    fn test_relay_interval_args() {
+        let config = Config::from_env_and_args(
+            vars(&[]),
+            args(&["--relay-interval=20", "--relay-idle-interval", "5000"]),
+        )
+        .unwrap();
+        assert_eq!(config.relay_interval, Duration::from_millis(20));
+        assert_eq!(config.relay_idle_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_cleanup_interval_arg() {
+        let config = Config::from_env_and_args(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.cleanup_interval, Duration::from_secs(1));
+        let config =
+            Config::from_env_and_args(vars(&[]), args(&["--cleanup-interval=250"])).unwrap();
+        assert_eq!(config.cleanup_interval, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        for invalid in [
+            &["--log-format", "xml"][..],
+            &["--log-format"],
+            &["--unknown", "1"],
+            &["--max-rooms", "many"],
+            &["--ping-interval=-1"],
+        ] {
+            assert!(Config::from_env_and_args(vars(&[]), args(invalid)).is_err());
+        }
+    }
+}
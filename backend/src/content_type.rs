@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+/// Content type of files with an unknown extension.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Maps file extensions to the content type files are served with.
+#[derive(Debug, Clone)]
+pub struct ContentTypes {
+    /// Lowercase extensions without the leading dot.
+    types: HashMap<String, String>,
+}
+
+impl ContentTypes {
+    /// Returns a map without any extension, so every file is served as
+    /// [DEFAULT_CONTENT_TYPE].
+    pub fn empty() -> Self {
+        Self {
+            types: HashMap::new(),
+        }
+    }
+
+    /// Maps `extension`, with or without the leading dot, to `content_type`,
+    /// replacing a previous mapping. Extensions are matched ignoring ASCII
+    /// case.
+    pub fn insert<V: Into<String>>(&mut self, extension: &str, content_type: V) -> &mut Self {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.types.insert(extension, content_type.into());
+        self
+    }
+
+    /// Returns the content type of the file at `path`, e.g. `text/css` for
+    /// `/styles/style.css`.
+    pub fn for_path(&self, path: &str) -> &str {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        file_name
+            .rsplit_once('.')
+            .and_then(|(_, extension)| self.types.get(&extension.to_ascii_lowercase()))
+            .map_or(DEFAULT_CONTENT_TYPE, String::as_str)
+    }
+}
+
+impl Default for ContentTypes {
+    fn default() -> Self {
+        let mut types = Self::empty();
+        types
+            // text is always served as utf-8, so browsers don't guess
+            .insert("html", "text/html; charset=utf-8")
+            .insert("htm", "text/html; charset=utf-8")
+            .insert("js", "text/javascript; charset=utf-8")
+            .insert("mjs", "text/javascript; charset=utf-8")
+            .insert("css", "text/css; charset=utf-8")
+            .insert("json", "application/json; charset=utf-8")
+            .insert("txt", "text/plain; charset=utf-8")
+            .insert("svg", "image/svg+xml")
+            .insert("png", "image/png")
+            .insert("jpg", "image/jpeg")
+            .insert("jpeg", "image/jpeg")
+            .insert("gif", "image/gif")
+            .insert("webp", "image/webp")
+            .insert("ico", "image/x-icon")
+            .insert("woff", "font/woff")
+            .insert("woff2", "font/woff2")
+            .insert("wasm", "application/wasm");
+        types
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_extensions() {
+        let types = ContentTypes::default();
+        assert_eq!(types.for_path("/index.html"), "text/html; charset=utf-8");
+        assert_eq!(
+            types.for_path("/scripts/chat.js"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            types.for_path("/styles/style.css"),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(types.for_path("/logo.SVG"), "image/svg+xml");
+        // binary types don't get a charset
+        assert_eq!(types.for_path("/app.wasm"), "application/wasm");
+        assert_eq!(types.for_path("/logo.png"), "image/png");
+    }
+
+    #[test]
+    fn test_unknown_extensions() {
+        let types = ContentTypes::default();
+        assert_eq!(types.for_path("/archive.tar.xz"), DEFAULT_CONTENT_TYPE);
+        assert_eq!(types.for_path("/chat"), DEFAULT_CONTENT_TYPE);
+        assert_eq!(types.for_path("/v1.2/readme"), DEFAULT_CONTENT_TYPE);
+        assert_eq!(types.for_path("/style.css/"), DEFAULT_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn test_custom_mapping() {
+        let mut types = ContentTypes::default();
+        types
+            .insert(".md", "text/markdown")
+            .insert("JS", "application/javascript");
+        assert_eq!(types.for_path("/README.md"), "text/markdown");
+        assert_eq!(types.for_path("/scripts/chat.js"), "application/javascript");
+    }
+}
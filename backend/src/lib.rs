@@ -1,10 +1,15 @@
+pub mod auth;
+pub mod content_type;
+pub mod rate_limit;
 pub mod request;
 pub mod response;
+pub mod router;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct HeaderName(String);
 
 impl HeaderName {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(src: &str) -> Self {
         Self(src.to_ascii_lowercase())
     }
@@ -14,3 +19,32 @@ impl HeaderName {
     }
 }
 
+/// Lets header maps be queried with lowercase `&str` keys.
+impl std::borrow::Borrow<str> for HeaderName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Returns whether the comma separated header value contains `token`, ignoring
+/// ASCII case, e.g. `upgrade` in `keep-alive, Upgrade`.
+pub fn contains_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains_token() {
+        assert!(contains_token("Upgrade", "upgrade"));
+        assert!(contains_token("keep-alive, Upgrade", "upgrade"));
+        assert!(contains_token("keep-alive,Upgrade ", "UPGRADE"));
+        assert!(!contains_token("keep-alive, Upgrade", "close"));
+        assert!(!contains_token("upgraded", "upgrade"));
+        assert!(!contains_token("", "upgrade"));
+    }
+}
@@ -1,48 +1,395 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
-use backend::HeaderName;
+use anyhow::Context;
 use rand::Rng;
-use sha1::{Digest, Sha1};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 
-use backend::request::{Method, Request};
+use backend::auth::{AllowAll, AuthInfo, JoinAuthorizer};
+use backend::content_type::ContentTypes;
+use backend::rate_limit::{IpConnectionGuard, IpConnectionLimit, TokenBucket};
+use backend::request::{Method, Request, Version};
 use backend::response::{Response, Status};
-use tokio::sync::Mutex;
+use backend::router::{Params, RouteError, Router};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
 use tokio::task;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, info, trace, warn};
-use websockets::WebSocket;
+use tracing_subscriber::EnvFilter;
+use websockets::frame::{Frame, OpCode};
+use websockets::{Message, MessageError, TrySendError, WebSocket};
 
-const MAX_ROOM_NUMBER: usize = 20;
+use config::{Config, LogFormat, SendPolicy};
+
+mod config;
+mod tls;
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_NICK_LEN: usize = 24;
+/// Number of rooms `/api/rooms` returns without and at most with `limit`.
+const DEFAULT_ROOM_LIST_LIMIT: usize = 50;
+const MAX_ROOM_LIST_LIMIT: usize = 100;
+/// Messages held back for a member under [SendPolicy::DropOldest] while its
+/// send queue is full.
+const MAX_OUTBOX_LEN: usize = 32;
+/// The only websocket protocol version the server speaks (RFC 6455).
+const WS_VERSION: &str = "13";
+/// Status code of the Close frame members of a closed room get.
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// Status code of the Close frame a client gets if its room stopped admitting
+/// it during the handshake, e.g. because it filled up.
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+/// Websocket extensions the server speaks, e.g. `permessage-deflate`. None
+/// yet, so clients offering some fall back to plain frames.
+const SUPPORTED_EXTENSIONS: &[&str] = &[];
 
-#[derive(Default)]
 struct AppData {
-    rooms: HashMap<String, RoomData>,
+    config: Config,
+    metrics: Metrics,
+    rooms: Mutex<HashMap<String, SharedRoomData>>,
+    connection_id_counter: AtomicUsize,
+    /// One permit per connection, see [Config::max_connections].
+    connection_permits: Arc<Semaphore>,
+    ip_connections: IpConnectionLimit,
+    router: Router<Handler>,
+    /// Content types of the [STATIC_FILES].
+    content_types: ContentTypes,
+    /// Issued reconnection tokens, see [Config::reconnect_grace].
+    reconnect_tokens: std::sync::Mutex<HashMap<String, ReconnectToken>>,
+    /// Decides who may join which room, lets everyone in unless another one
+    /// is given to [AppData::with_authorizer].
+    authorizer: Box<dyn JoinAuthorizer>,
+}
+
+/// The identity a reconnection token restores.
+#[derive(Debug, Clone)]
+struct ReconnectToken {
+    room: String,
+    id: usize,
+    nick: Option<String>,
+    /// When the member left the room, `None` while it is connected.
+    left_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Messages delivered to a peer, counted once per recipient.
+    messages_relayed: AtomicU64,
+    /// Payload bytes delivered to peers, counted once per recipient.
+    bytes_relayed: AtomicU64,
+    /// Websocket connections refused during the upgrade.
+    connections_rejected: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts messages that reached a member's send queue.
+    fn count_relayed(&self, queued: Queued) {
+        self.messages_relayed
+            .fetch_add(queued.messages, Ordering::Relaxed);
+        self.bytes_relayed
+            .fetch_add(queued.bytes, Ordering::Relaxed);
+    }
 }
 
 struct RoomData {
-    pub sockets: HashMap<usize, WebSocket>,
+    pub sockets: HashMap<usize, Member>,
     pub is_deletable: bool,
+    /// Set when the room is taken out of [AppData::rooms], so whoever locks
+    /// it afterwards through an old [Arc] knows it is gone.
+    pub is_removed: bool,
+    pub options: RoomOptions,
+    pub created_at: Instant,
+    /// The most recent messages, oldest first, see [RoomOptions::history_size].
+    pub history: VecDeque<Arc<Message>>,
+    /// Payload bytes delivered to the room's members, counted once per
+    /// recipient.
+    pub bytes_relayed: u64,
+    /// Notified by the members' sockets whenever they received something, so
+    /// the idle relay task wakes up right away.
+    pub activity: Arc<Notify>,
+}
+
+/// Settings of a single room, see [RoomData::with_options].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RoomOptions {
+    /// Members the room admits at the same time. `None` admits any number.
+    max_members: Option<usize>,
+    /// Permanent rooms are never removed, even when empty.
+    permanent: bool,
+    /// Number of recent messages the room keeps to replay to new members.
+    history_size: usize,
+    /// Password that has to be passed as `password` query parameter to join,
+    /// set through the body of `POST /api/gen-room`.
+    password: Option<String>,
+}
+
+/// Messages that reached a member's send queue, see [Member::relay].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Queued {
+    messages: u64,
+    /// Payload bytes of the messages.
+    bytes: u64,
+}
+
+impl Queued {
+    fn one(message: &Message) -> Self {
+        Self {
+            messages: 1,
+            bytes: message.len() as u64,
+        }
+    }
+}
+
+struct Member {
+    pub socket: WebSocket,
+    rate_limiter: TokenBucket,
+    rate_violations: u32,
+    max_rate_violations: Option<u32>,
+    /// Relay cycles in a row the member's send queue has been full.
+    full_send_cycles: u32,
+    max_full_send_cycles: Option<u32>,
+    send_policy: SendPolicy,
+    /// Messages waiting for room in the send queue, see [Member::relay].
+    outbox: VecDeque<Arc<Message>>,
+    /// Name the member chose when connecting, unique within the room.
+    nick: Option<String>,
+    /// Counts the connection against the connection limits until the member
+    /// is removed.
+    connection_permit: Option<ConnectionPermit>,
+    /// Lets the member rejoin after its connection dropped, see
+    /// [AppData::issue_reconnect_token].
+    reconnect_token: Option<String>,
+    /// Who the [AppData::authorizer] found the member to be.
+    auth: AuthInfo,
+}
+
+/// Counts a connection against the connection limits until dropped.
+struct ConnectionPermit {
+    _global: OwnedSemaphorePermit,
+    /// `None` until the client address is known, see
+    /// [AppData::try_acquire_connection].
+    per_ip: Option<IpConnectionGuard>,
+}
+
+/// Why a member is removed from its room.
+#[derive(Debug)]
+enum RemovalReason {
+    /// Receiving from or sending to the member failed, which includes regular
+    /// closes.
+    Error(MessageError),
+    /// The member exceeded the rate limit too often.
+    RateLimit,
+    /// The member didn't read the messages sent to it for too long.
+    SlowConsumer,
+    /// An admin closed the member's room.
+    RoomClosed,
+}
+
+/// Application level control messages, sent as json text messages. The relay
+/// answers them itself instead of passing them on to the room.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type")]
+enum ControlMessage {
+    /// Sent by a client to check that its connection is alive.
+    #[serde(rename = "hb")]
+    Heartbeat,
+    /// The answer to a [ControlMessage::Heartbeat].
+    #[serde(rename = "hb-ack")]
+    HeartbeatAck,
+    /// The current members of the room, see [Config::broadcast_members].
+    #[serde(rename = "members")]
+    Members { members: Vec<MemberInfo> },
+    /// A message for a single member, see [RoomData::send_direct]. Clients
+    /// address it with `to`, the relay delivers it with `from` instead.
+    #[serde(rename = "dm")]
+    Direct {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<usize>,
+        text: String,
+    },
+    /// Sent to a client right after joining, with the token to rejoin with
+    /// after the connection dropped.
+    #[serde(rename = "welcome")]
+    Welcome { id: usize, token: String },
+    /// Tells a client that something it sent couldn't be handled.
+    #[serde(rename = "error")]
+    Error { message: String },
+    /// A member joined the room, see [Config::member_events].
+    #[serde(rename = "join")]
+    Join(MemberInfo),
+    /// A member left the room, see [Config::member_events].
+    #[serde(rename = "leave")]
+    Leave(MemberInfo),
+    /// A notice from the server to everyone in the room.
+    #[serde(rename = "system")]
+    System { message: String },
+    /// The room's recent text messages, oldest first, sent to a new member
+    /// right after the welcome so it can tell them from live ones, see
+    /// [RoomOptions::history_size].
+    #[serde(rename = "history")]
+    History { messages: Vec<String> },
+}
+
+/// A room member as listed by `/api/rooms/:name/members`,
+/// [ControlMessage::Members] and the join and leave events.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct MemberInfo {
+    id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nick: Option<String>,
+}
+
+/// A client connection, either a plain tcp stream or a TLS stream on top of
+/// one.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// The outcome of a successful websocket handshake.
+#[derive(Debug)]
+struct Upgrade {
+    response: Response,
+    room: String,
+    protocol: Option<String>,
+    /// Extensions negotiated through `Sec-WebSocket-Extensions`.
+    extensions: Vec<String>,
+    /// The sanitized `nick` query parameter, see [sanitize_nick].
+    nick: Option<String>,
+    /// The `password` query parameter, see [RoomOptions::password].
+    password: Option<String>,
+    /// The `token` query parameter, see [AppData::redeem_reconnect_token].
+    token: Option<String>,
 }
 
-type SharedAppData = Arc<Mutex<AppData>>;
+type SharedAppData = Arc<AppData>;
+type SharedRoomData = Arc<Mutex<RoomData>>;
+/// Frontend files with the path they are served under and their file name,
+/// which determines their content type.
+const STATIC_FILES: &[(&str, &str, &str)] = &[
+    (
+        "/index.html",
+        "index.html",
+        include_str!("../../frontend/index.html"),
+    ),
+    (
+        "/chat",
+        "chat.html",
+        include_str!("../../frontend/chat.html"),
+    ),
+    (
+        "/scripts/chat.js",
+        "chat.js",
+        include_str!("../../frontend/scripts/chat.js"),
+    ),
+    (
+        "/scripts/index.js",
+        "index.js",
+        include_str!("../../frontend/scripts/index.js"),
+    ),
+    (
+        "/styles/style.css",
+        "style.css",
+        include_str!("../../frontend/styles/style.css"),
+    ),
+];
+
+/// Handles a routed request, see [routes].
+type Handler = Box<
+    dyn for<'a> Fn(
+            &'a Request,
+            &'a SharedAppData,
+            Params,
+        ) -> Pin<Box<dyn Future<Output = Response> + Send + 'a>>
+        + Send
+        + Sync,
+>;
+
+/// Boxes `handler` for [routes], which lets the compiler infer the lifetimes
+/// of a closure's arguments.
+fn handler<F>(handler: F) -> Handler
+where
+    F: for<'a> Fn(
+            &'a Request,
+            &'a SharedAppData,
+            Params,
+        ) -> Pin<Box<dyn Future<Output = Response> + Send + 'a>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Box::new(handler)
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::TRACE)
-        .init();
+    let config = Config::from_env_and_args(std::env::vars(), std::env::args().skip(1))?;
+    tracing::subscriber::set_global_default(build_subscriber(&config))?;
+    if let Some(name) = config.default_room.as_deref() {
+        if !is_valid_room_name(name) {
+            anyhow::bail!("invalid default room name {}", name);
+        }
+    }
     info!("starting server.");
-    let (ip, port) = ("0.0.0.0", 8080);
-    let server = TcpListener::bind((ip, port)).await?;
+    let (ip, port) = (config.host.as_str(), config.port);
+    let server = TcpListener::bind((ip, port))
+        .await
+        .with_context(|| format!("failed to bind to {}:{}", ip, port))?;
     info!(ip, port, "bound tcp server.");
-    let rooms = HashMap::from([(String::from("roomForAll"), RoomData::new())]);
-    let app_data: SharedAppData = Arc::new(Mutex::new(AppData { rooms }));
+    let tls = match &config.tls {
+        Some(files) => Some(tls::load_acceptor(&files.cert, &files.key)?),
+        None => None,
+    };
+    let app_data: SharedAppData = Arc::new(AppData::new(config));
+    add_default_room(&app_data).await;
+
+    let _cleanup_task = task::spawn(room_cleanup_task(Arc::clone(&app_data)));
+
+    serve(server, app_data, tls).await
+}
+
+/// Adds the permanent room named in [Config::default_room], if any.
+async fn add_default_room(app_data: &SharedAppData) {
+    let name = if let Some(name) = &app_data.config.default_room {
+        name
+    } else {
+        return;
+    };
+    let room = RoomData::with_options(RoomOptions {
+        permanent: true,
+        ..RoomOptions::from_config(&app_data.config)
+    });
+    let room = room.spawn_shared(name, app_data);
+    app_data.rooms.lock().await.insert(name.clone(), room);
+}
 
-    let _listener_task = task::spawn(msg_listener_task(Arc::clone(&app_data)));
+/// Builds the log subscriber. The log level is read from `RUST_LOG` and
+/// defaults to [Config::log_level].
+fn build_subscriber(config: &Config) -> Box<dyn tracing::Subscriber + Send + Sync> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Pretty => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
 
+/// Accepts connections and handles each of them in its own task, so a slow
+/// client doesn't keep others from being served. With `tls`, every connection
+/// has to complete a TLS handshake first.
+async fn serve(server: TcpListener, app_data: SharedAppData, tls: Option<TlsAcceptor>) -> ! {
     loop {
-        let (mut stream, _) = if let Ok(stream) = server.accept().await {
+        let (stream, addr) = if let Ok(stream) = server.accept().await {
             info!(
                 addr = stream.1.to_string(),
                 "successfully accepted new tcp stream."
@@ -52,159 +399,725 @@ async fn main() -> anyhow::Result<()> {
             debug!("failed to accept tcp stream.");
             continue;
         };
-        let request = if let Ok(req) = Request::try_parse_from(&mut stream).await {
-            info!(
-                method = req.method().to_string(),
-                path = req.path(),
-                "successfully parsed request."
-            );
-            req
-        } else {
+        let permit = app_data.try_acquire_connection(addr.ip());
+        let app_data = Arc::clone(&app_data);
+        let tls = tls.clone();
+        task::spawn(async move {
+            let mut stream = if let Some(stream) = accept_tls(stream, tls, &app_data.config).await {
+                stream
+            } else {
+                return;
+            };
+            match permit {
+                Ok(permit) => handle_connection(stream, addr, app_data, permit).await,
+                Err(status) => {
+                    info!(?status, "too many connections.");
+                    let _ = Response::builder()
+                        .with_status(status)
+                        .with_header("connection", "close")
+                        .with_body(Vec::new())
+                        .try_write_to(&mut stream)
+                        .await;
+                }
+            }
+        });
+    }
+}
+
+/// Completes the TLS handshake if `tls` is set. Returns `None` if the
+/// handshake fails or takes longer than a request may take.
+async fn accept_tls(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    config: &Config,
+) -> Option<Box<dyn Io>> {
+    let acceptor = if let Some(acceptor) = tls {
+        acceptor
+    } else {
+        return Some(Box::new(stream));
+    };
+    match tokio::time::timeout(config.request_timeout, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(Box::new(stream)),
+        Ok(Err(e)) => {
+            debug!(%e, "tls handshake failed.");
+            None
+        }
+        Err(_) => {
+            debug!("tls handshake timed out.");
+            None
+        }
+    }
+}
+
+/// Reads requests from a freshly accepted stream and handles them until the
+/// client closes the connection or upgrades it to a websocket. Clients that
+/// don't send a complete request in time get a `408 Request Timeout`.
+#[tracing::instrument(
+    skip(stream, app_data, permit),
+    fields(client_ip = tracing::field::Empty)
+)]
+async fn handle_connection<S: Io + 'static>(
+    stream: S,
+    addr: SocketAddr,
+    app_data: SharedAppData,
+    mut permit: ConnectionPermit,
+) {
+    let config = &app_data.config;
+    // the reader keeps bytes read past one request for the next one
+    let mut stream = BufReader::new(stream);
+    let mut is_first_request = true;
+    loop {
+        if !is_first_request {
+            let idle = tokio::time::timeout(config.keep_alive_timeout, stream.fill_buf()).await;
+            if !matches!(idle, Ok(Ok(buf)) if !buf.is_empty()) {
+                debug!("closing idle connection.");
+                return;
+            }
+        }
+        is_first_request = false;
+
+        let parse = Request::try_parse_with_limits(&mut stream, &config.request_limits);
+        let request = match tokio::time::timeout(config.request_timeout, parse).await {
+            Ok(Ok(req)) => {
+                info!(
+                    method = req.method().to_string(),
+                    path = req.path(),
+                    "successfully parsed request."
+                );
+                req
+            }
+            Ok(Err(e)) => {
+                info!(error = %e, "failed to parse request.");
+                let mut response = Response::from_error(&e);
+                response.set_header("connection", "close");
+                let _ = response.try_write_to(&mut stream).await;
+                return;
+            }
+            Err(_) => {
+                info!("timed out reading request.");
+                let response = Response::builder()
+                    .with_status(Status::RequestTimeout)
+                    .with_header("connection", "close")
+                    .with_body(Vec::new());
+                let _ = response.try_write_to(&mut stream).await;
+                return;
+            }
+        };
+
+        if request.remote_version().is_none() {
+            info!(version = request.version(), "unsupported http version.");
             let response = Response::builder()
-                .with_status(Status::BadRequest)
+                .with_status(Status::HttpVersionNotSupported)
+                .with_header("connection", "close")
                 .with_body(Vec::new());
             let _ = response.try_write_to(&mut stream).await;
-            continue;
-        };
-        let _ = handle(request, stream, Arc::clone(&app_data)).await;
+            return;
+        }
+
+        let ip = client_ip(&request, addr.ip(), config.trust_proxy);
+        tracing::Span::current().record("client_ip", tracing::field::display(ip));
+        if let Err(status) = app_data.assign_client_ip(&mut permit, ip) {
+            info!(?status, "too many connections.");
+            let response = Response::builder()
+                .with_status(status)
+                .with_header("connection", "close")
+                .with_body(Vec::new());
+            let _ = response.try_write_to(&mut stream).await;
+            return;
+        }
+
+        if request.method() == Method::Get && request.path().starts_with("/ws") {
+            // the reader is kept, as a client that doesn't wait for the
+            // handshake response may have sent frames along with the request
+            handle_new_ws(&request, stream, addr, app_data, permit).await;
+            return;
+        }
+
+        let keep_alive = wants_keep_alive(&request);
+        let mut response = handle(&request, &app_data).await;
+        response.set_header(
+            "connection",
+            if keep_alive { "keep-alive" } else { "close" },
+        );
+        if let Err(e) = response.try_write_to(&mut stream).await {
+            debug!(?e, "error writing response to stream.");
+            return;
+        }
+        info!("successfully sent response");
+        if !keep_alive {
+            return;
+        }
     }
 }
 
-#[tracing::instrument(skip(app_data))]
-async fn msg_listener_task(app_data: SharedAppData) {
-    loop {
-        let mut data = app_data.lock().await;
-        let mut delete_rooms = Vec::new();
-        for (room_name, room) in &mut data.rooms {
-            let mut delete_members = Vec::new();
-            // collect messages
-            let mut messages = Vec::with_capacity(room.sockets.len());
-            for (&id, socket) in &room.sockets {
-                match socket.poll_next_message().await {
-                    Some(Err(e)) => {
-                        debug!(error = ?e, id, "error while polling next message.");
-                        delete_members.push(id);
+/// Returns the address of the client that sent `request`. Behind a trusted
+/// proxy this is the leftmost `X-Forwarded-For` entry, otherwise, or if the
+/// header is missing or invalid, the address of the peer.
+fn client_ip(request: &Request, peer_ip: IpAddr, trust_proxy: bool) -> IpAddr {
+    let forwarded = request
+        .header("x-forwarded-for")
+        .filter(|_| trust_proxy)
+        .and_then(|value| value.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    forwarded.unwrap_or(peer_ip)
+}
+
+/// Returns whether the connection may be reused after answering `request`.
+/// HTTP/1.1 connections are kept alive unless the client asks to close them,
+/// HTTP/1.0 connections only if the client asks to keep them.
+fn wants_keep_alive(request: &Request) -> bool {
+    if request.header_contains_token("connection", "close") {
+        false
+    } else if request.header_contains_token("connection", "keep-alive") {
+        true
+    } else {
+        request.remote_version() == Some(Version::Http11)
+    }
+}
+
+/// Relays messages between the members of a single room. Every room runs its
+/// own relay task, so a slow room doesn't hold up the others. The task ends
+/// once the room has been removed from [AppData].
+#[tracing::instrument(skip(weak_room, app_data))]
+async fn room_relay_task(
+    room_name: String,
+    weak_room: Weak<Mutex<RoomData>>,
+    app_data: SharedAppData,
+) {
+    let config = &app_data.config;
+    let mut last_ping = Instant::now();
+    while let Some(room) = weak_room.upgrade() {
+        let mut room = room.lock().await;
+        let is_active = relay_room_messages(&room_name, &mut room, &app_data).await;
+        let mut idle_interval = config.relay_idle_interval;
+        if let Some(interval) = config.ping_interval {
+            if last_ping.elapsed() >= interval {
+                room.ping_members();
+                last_ping = Instant::now();
+            }
+            idle_interval = idle_interval.min(interval.saturating_sub(last_ping.elapsed()));
+        }
+        let activity = Arc::clone(&room.activity);
+        drop(room);
+        if is_active {
+            tokio::time::sleep(config.relay_interval).await;
+        } else {
+            // nothing to do until a member sends something or leaves
+            let _ = tokio::time::timeout(idle_interval, activity.notified()).await;
+        }
+    }
+    debug!("room dropped, stopping relay task.");
+}
+
+/// Relays the messages the members sent since the last call. Returns whether
+/// there was anything to do, i.e. whether the next call should come soon.
+async fn relay_room_messages(room_name: &str, room: &mut RoomData, app_data: &AppData) -> bool {
+    let member_count = room.sockets.len();
+    let mut is_active = false;
+    let mut delete_members = Vec::new();
+    // collect messages
+    let mut messages = Vec::with_capacity(room.sockets.len());
+    let mut direct_messages = Vec::new();
+    for (&id, member) in &mut room.sockets {
+        match member.flush_outbox() {
+            Ok(queued) => {
+                app_data.metrics.count_relayed(queued);
+                room.bytes_relayed += queued.bytes;
+            }
+            Err(reason) => {
+                delete_members.push((id, reason));
+                continue;
+            }
+        }
+        // backed up members have to be checked every cycle, so slow ones are
+        // removed in time
+        if !member.outbox.is_empty() || member.socket.is_send_queue_full() {
+            is_active = true;
+        }
+        if member.is_slow_consumer() {
+            debug!(
+                id,
+                room_name,
+                pending = member.socket.pending_sends(),
+                "send queue stayed full."
+            );
+            delete_members.push((id, RemovalReason::SlowConsumer));
+            continue;
+        }
+        let polled = member.socket.poll_next_message().await;
+        is_active |= polled.is_some();
+        match polled {
+            Some(Err(e)) => {
+                debug!(error = ?e, id, room_name, "error while polling next message.");
+                delete_members.push((id, RemovalReason::Error(e)));
+            }
+            Some(Ok(msg)) if member.is_rate_limited() => {
+                debug!(?msg, id, room_name, "dropping rate limited message.");
+                if member.exceeds_rate_violations() {
+                    debug!(id, room_name, "too many rate limited messages.");
+                    delete_members.push((id, RemovalReason::RateLimit));
+                }
+            }
+            Some(Ok(msg)) => match ControlMessage::parse(&msg) {
+                Some(ControlMessage::Heartbeat) => {
+                    trace!(id, room_name, "answering heartbeat.");
+                    let ack = Arc::new(ControlMessage::HeartbeatAck.to_message());
+                    match member.socket.try_send_now(ack) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            debug!(id, room_name, "send queue full, skipping heartbeat ack.");
+                        }
+                        Err(error) => {
+                            debug!(?error, id, room_name, "error answering heartbeat.");
+                            let error = MessageError::SendFailed;
+                            delete_members.push((id, RemovalReason::Error(error)));
+                        }
                     }
-                    Some(Ok(msg)) => {
+                }
+                Some(ControlMessage::Direct {
+                    to: Some(to), text, ..
+                }) => {
+                    trace!(id, to, room_name, "collecting direct message.");
+                    direct_messages.push((id, to, text));
+                }
+                Some(control) => {
+                    // only the server may send the others
+                    debug!(?control, id, room_name, "dropping control message.");
+                }
+                None => {
+                    let max_len = app_data.config.max_chat_len;
+                    if let Some(max_len) = max_len.filter(|&max| is_text_longer(&msg, max)) {
+                        debug!(id, room_name, max_len, "dropping oversized message.");
+                        let error = ControlMessage::Error {
+                            message: format!("message longer than {} characters", max_len),
+                        };
+                        if let Err(error) = member.socket.try_send_now(Arc::new(error.to_message()))
+                        {
+                            debug!(?error, id, room_name, "error sending error message.");
+                        }
+                    } else {
                         trace!(?msg, id, room_name);
-                        messages.push((id, msg));
+                        messages.push((id, Arc::new(msg)));
                     }
-                    None => {}
                 }
+            },
+            None if member.socket.is_closed() => {
+                // the stream task ended without leaving an error behind
+                let error = MessageError::ConnectionClosed(None);
+                delete_members.push((id, RemovalReason::Error(error)));
+            }
+            None => {}
+        }
+    }
+    remove_members(room_name, room, delete_members, app_data);
+    for (from, to, text) in direct_messages {
+        room.send_direct(from, to, text);
+    }
+    // send messages
+    let mut failed_peers = Vec::new();
+    for (sender_id, message) in messages {
+        if room.options.history_size > 0 {
+            if room.history.len() >= room.options.history_size {
+                room.history.pop_front();
+            }
+            room.history.push_back(Arc::clone(&message));
+        }
+        for (&peer_id, peer) in &mut room.sockets {
+            if peer_id == sender_id || failed_peers.iter().any(|(id, _)| *id == peer_id) {
+                continue;
             }
-            // cleanup
-            for id in delete_members {
-                debug!(id, room_name, "removing member from room.");
-                room.sockets.remove(&id);
-            }
-            if room.sockets.len() == 0 && room.is_deletable {
-                delete_rooms.push(room_name.clone());
-            }
-            // send messages
-            for (sender_id, message) in messages {
-                for (peer_id, socket) in room.sockets.iter().filter(|(&id, _)| id != sender_id) {
-                    trace!(sender_id, peer_id, "sending message to other room member.");
-                    let r = socket.try_send(message.clone()).await;
-                    if let Err(error) = r {
-                        debug!(?error, sender_id, peer_id, "error sending message.");
+            trace!(sender_id, peer_id, "sending message to other room member.");
+            match peer.relay(Arc::clone(&message)).await {
+                Ok(queued) => {
+                    if queued.messages == 0 {
+                        debug!(sender_id, peer_id, "message not queued for backed up peer.");
                     }
+                    app_data.metrics.count_relayed(queued);
+                    room.bytes_relayed += queued.bytes;
+                }
+                Err(reason) => {
+                    debug!(?reason, sender_id, peer_id, "error sending message.");
+                    failed_peers.push((peer_id, reason));
                 }
             }
         }
-        for room_name in delete_rooms {
-            info!(room_name, "removing room");
-            data.rooms.remove(&room_name);
+    }
+    remove_members(room_name, room, failed_peers, app_data);
+    if app_data.config.broadcast_members && room.sockets.len() < member_count {
+        room.broadcast_members();
+    }
+    is_active
+}
+
+/// Returns whether `msg` is a text message with more than `max` characters.
+fn is_text_longer(msg: &Message, max: usize) -> bool {
+    // a text can't have more characters than bytes, so most aren't counted
+    matches!(msg, Message::Text(text) if text.len() > max && text.chars().count() > max)
+}
+
+fn remove_members(
+    room_name: &str,
+    room: &mut RoomData,
+    members: Vec<(usize, RemovalReason)>,
+    app_data: &AppData,
+) {
+    for (id, reason) in members {
+        let member = match room.sockets.remove(&id) {
+            Some(member) => member,
+            None => continue,
+        };
+        if let Some(token) = &member.reconnect_token {
+            app_data.release_reconnect_token(token);
+        }
+        let peer_addr = member.socket.peer_addr();
+        info!(id, nick = member.nick.as_deref(), room_name, "member left.");
+        if app_data.config.member_events {
+            let nick = member.nick;
+            room.broadcast(&ControlMessage::Leave(MemberInfo { id, nick }));
+        }
+        match reason {
+            RemovalReason::Error(error) => {
+                debug!(
+                    ?error,
+                    id,
+                    ?peer_addr,
+                    room_name,
+                    "removing member after error."
+                )
+            }
+            RemovalReason::RateLimit => {
+                debug!(id, ?peer_addr, room_name, "removing rate limited member.")
+            }
+            RemovalReason::SlowConsumer => {
+                debug!(id, ?peer_addr, room_name, "removing slow member.")
+            }
+            RemovalReason::RoomClosed => {
+                debug!(id, ?peer_addr, room_name, "removing member of closed room.")
+            }
         }
-        drop(data);
-        // 120 Hz
-        tokio::time::sleep(std::time::Duration::from_millis(8)).await;
     }
 }
 
-#[tracing::instrument(skip(req, stream, app_data), fields(http.ip = ?stream.peer_addr()))]
-async fn handle(
-    req: Request,
-    mut stream: TcpStream,
-    app_data: SharedAppData,
-) -> anyhow::Result<()> {
-    match (req.method(), req.path()) {
-        (Method::Get, path) if path.starts_with("/chat") => {
-            let html = include_str!("../../frontend/chat.html");
-            Response::builder()
-                .as_html()
-                .with_body(html)
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
+#[tracing::instrument(skip(app_data))]
+async fn room_cleanup_task(app_data: SharedAppData) {
+    loop {
+        remove_empty_rooms(&app_data).await;
+        app_data.remove_expired_reconnect_tokens();
+        tokio::time::sleep(app_data.config.cleanup_interval).await;
+    }
+}
+
+async fn remove_empty_rooms(app_data: &AppData) {
+    let mut rooms = app_data.rooms.lock().await;
+    // rooms that are currently locked are in use and therefore skipped, which
+    // keeps the map lock from being held while waiting on a busy room.
+    rooms.retain(|room_name, room| match room.try_lock() {
+        Ok(room) if room.options.permanent => true,
+        Ok(mut room) if room.sockets.is_empty() && room.is_deletable => {
+            info!(room_name, "removing room");
+            room.is_removed = true;
+            false
         }
-        (Method::Get, "/scripts/chat.js") => {
-            Response::builder()
-                .as_js()
-                .with_body(include_str!("../../frontend/scripts/chat.js"))
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
+        Ok(mut room)
+            if room.sockets.is_empty() && room.created_at.elapsed() >= app_data.config.room_ttl =>
+        {
+            info!(room_name, "removing abandoned room");
+            room.is_removed = true;
+            false
         }
-        (Method::Get, "/scripts/index.js") => {
-            Response::builder()
-                .as_js()
-                .with_body(include_str!("../../frontend/scripts/index.js"))
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
+        _ => true,
+    });
+}
+
+/// Builds the response to a request that doesn't upgrade to a websocket.
+#[tracing::instrument(skip(req, app_data))]
+async fn handle(req: &Request, app_data: &SharedAppData) -> Response {
+    match app_data.router.route(req.method(), req.path()) {
+        Ok(found) => (found.handler)(req, app_data, found.params).await,
+        Err(RouteError::NotFound) => {
+            let message = format!("no resource with path {} found", req.path());
+            error_response(req, Status::NotFound, &message)
         }
-        (Method::Get, "/styles/style.css") => {
-            Response::builder()
-                .as_css()
-                .with_body(include_str!("../../frontend/styles/style.css"))
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
-        }
-        (Method::Get, path) if path.starts_with("/ws") => {
-            handle_new_ws(&req, stream, app_data).await;
-        }
-        (Method::Get, "/") | (Method::Get, "/index.html") => {
-            // serve index html
-            let html = include_str!("../../frontend/index.html");
-            Response::builder()
-                .as_html()
-                .with_body(html)
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
+        Err(RouteError::MethodNotAllowed(methods)) => {
+            let allow = methods
+                .iter()
+                .map(Method::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let message = format!(
+                "method {} not allowed for path {}",
+                req.method(),
+                req.path()
+            );
+            let mut response = error_response(req, Status::MethodNotAllowed, &message);
+            response.set_header("allow", allow);
+            response
         }
-        (Method::Get, "/api/rooms") => {
-            let names = app_data
-                .lock()
-                .await
-                .rooms
-                .keys()
-                .cloned()
-                .collect::<Vec<_>>();
-            Response::builder()
-                .as_json()
-                .with_body(format!("{:?}", names))
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
-        }
-        (Method::Get, "/api/gen-room") => {
-            info!("room creation requested");
-            let resp = handle_new_room(app_data).await;
-            resp.try_write_to(&mut stream).await?;
-            info!("successfully sent response ");
-        }
-        (_, path) => {
-            Response::builder()
-                .with_status(Status::NotFound)
-                .with_body(format!("Error 404: no resource with path {} found", path))
-                .try_write_to(&mut stream)
-                .await?;
-            info!("successfully sent response");
+    }
+}
+
+/// Builds the response for a request that failed with `status`. The body
+/// explains why, as json if the client accepts it and as plain text
+/// otherwise.
+fn error_response(request: &Request, status: Status, message: &str) -> Response {
+    let accepts_json = request.header("accept").is_some_and(|accept| {
+        accept.split(',').any(|media_range| {
+            let media_type = media_range.split(';').next().unwrap_or_default();
+            media_type.trim().eq_ignore_ascii_case("application/json")
+        })
+    });
+    Response::error(status, message, accepts_json)
+}
+
+/// The routes served by [handle].
+fn routes() -> Router<Handler> {
+    let mut router: Router<Handler> = Router::new();
+    router
+        .add(
+            Method::Get,
+            "/",
+            handler(|_, _, _| Box::pin(async { Response::redirect(Status::Found, "/index.html") })),
+        )
+        .add(
+            Method::Get,
+            "/api/rooms",
+            handler(|req, app_data, _| Box::pin(handle_room_list(req, app_data))),
+        )
+        .add(
+            Method::Get,
+            "/api/rooms/:name/members",
+            handler(|req, app_data, params| {
+                Box::pin(async move { handle_room_members(req, &params["name"], app_data).await })
+            }),
+        )
+        .add(
+            Method::Delete,
+            "/api/rooms/:name",
+            handler(|req, app_data, params| {
+                Box::pin(async move {
+                    let name = &params["name"];
+                    info!(name, "room deletion requested");
+                    handle_delete_room(req, name, app_data).await
+                })
+            }),
+        )
+        .add(
+            Method::Post,
+            "/api/admin/rooms/:name/close",
+            handler(|req, app_data, params| {
+                Box::pin(async move {
+                    let name = &params["name"];
+                    info!(name, "room close requested");
+                    handle_close_room(req, name, app_data).await
+                })
+            }),
+        )
+        .add(
+            Method::Post,
+            "/api/admin/announce",
+            handler(|req, app_data, _| {
+                info!("announcement requested");
+                Box::pin(handle_announce(req, app_data))
+            }),
+        )
+        .add(
+            Method::Get,
+            "/api/gen-room",
+            handler(|_, app_data, _| {
+                info!("room creation requested");
+                Box::pin(handle_new_room(Arc::clone(app_data)))
+            }),
+        )
+        .add(
+            Method::Post,
+            "/api/gen-room",
+            handler(|req, app_data, _| {
+                info!("named room creation requested");
+                Box::pin(handle_new_named_room(req, Arc::clone(app_data)))
+            }),
+        )
+        .add(
+            Method::Get,
+            "/metrics",
+            handler(|_, app_data, _| Box::pin(handle_metrics(app_data))),
+        )
+        .add(
+            Method::Get,
+            "/api/health",
+            handler(|_, app_data, _| Box::pin(handle_health(app_data))),
+        );
+    for &(path, file_name, contents) in STATIC_FILES {
+        router.add(
+            Method::Get,
+            path,
+            handler(move |_, app_data, _| {
+                Box::pin(async move { handle_static_file(file_name, contents, app_data) })
+            }),
+        );
+    }
+    router
+}
+
+/// Serves `contents`, one of the [STATIC_FILES], with the content type
+/// `file_name` maps to.
+fn handle_static_file(file_name: &str, contents: &'static str, app_data: &AppData) -> Response {
+    Response::builder()
+        .with_header("content-type", app_data.content_types.for_path(file_name))
+        .with_body(contents)
+}
+
+/// Lists the rooms sorted by name, a page of `limit` rooms starting at
+/// `offset` at a time. `prefix` only lists rooms whose name starts with it.
+/// Password protected rooms are only listed for admins, see
+/// [authorize_admin].
+async fn handle_room_list(req: &Request, app_data: &AppData) -> Response {
+    let (mut limit, mut offset) = (DEFAULT_ROOM_LIST_LIMIT, 0);
+    let mut prefix = "";
+    for (key, value) in get_query_params(req.path()) {
+        match key {
+            "limit" | "offset" => {
+                let number = if let Ok(number) = value.parse() {
+                    number
+                } else {
+                    let message = format!("invalid {} {}, expected a number", key, value);
+                    return error_response(req, Status::BadRequest, &message);
+                };
+                if key == "limit" {
+                    limit = usize::min(number, MAX_ROOM_LIST_LIMIT);
+                } else {
+                    offset = number;
+                }
+            }
+            "prefix" => prefix = value,
+            _ => {}
         }
-    };
-    Ok(())
+    }
+
+    let is_admin = authorize_admin(req, &app_data.config).is_ok();
+    // the map isn't held while waiting on busy rooms
+    let rooms = app_data
+        .rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(name, _)| name.starts_with(prefix))
+        .map(|(name, room)| (name.clone(), Arc::clone(room)))
+        .collect::<Vec<_>>();
+    let mut names = Vec::new();
+    for (name, room) in rooms {
+        let room = room.lock().await;
+        if !room.is_removed && (is_admin || room.options.password.is_none()) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    let total = names.len();
+    let rooms = names.into_iter().skip(offset).take(limit).collect();
+    Response::builder().with_json(&RoomList { total, rooms })
+}
+
+async fn handle_room_members(req: &Request, name: &str, app_data: &AppData) -> Response {
+    if let Some(room) = app_data.lock_room(name).await {
+        Response::builder().with_json(&room.member_list())
+    } else {
+        let message = format!("no room with name {} found", name);
+        error_response(req, Status::NotFound, &message)
+    }
+}
+
+/// Body of `/api/rooms`.
+#[derive(Serialize)]
+struct RoomList {
+    /// Number of listed rooms across all pages.
+    total: usize,
+    /// Names of the rooms on the requested page.
+    rooms: Vec<String>,
+}
+
+/// Body of requests to `POST /api/admin/announce`.
+#[derive(Deserialize)]
+struct Announcement {
+    message: String,
+}
+
+/// Body of `POST /api/admin/announce`, with the number of members the
+/// announcement was queued for.
+#[derive(Serialize)]
+struct AnnouncementSent {
+    recipients: usize,
+}
+
+/// Body of `POST /api/admin/rooms/:name/close`.
+#[derive(Serialize)]
+struct ClosedRoom {
+    disconnected: usize,
+}
+
+/// Body of `/api/health`.
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    rooms: usize,
+    connections: usize,
+}
+
+/// Body of `POST /api/gen-room`.
+#[derive(Deserialize)]
+struct NewRoom {
+    name: String,
+    /// Password the room asks for, see [RoomOptions::password].
+    password: Option<String>,
+}
+
+/// Body of `/api/gen-room`. `status` is 0 if the room was created.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum GenRoom {
+    Created { status: u8, name: String },
+    Denied { status: u8, message: &'static str },
+}
+
+async fn handle_health(app_data: &AppData) -> Response {
+    let (rooms, connections) = app_data.count_connections().await;
+    Response::builder().with_json(&Health {
+        status: "ok",
+        rooms,
+        connections,
+    })
+}
+
+async fn handle_metrics(app_data: &AppData) -> Response {
+    let (rooms, connections) = app_data.count_connections().await;
+    let metrics = &app_data.metrics;
+    let mut body = format!(
+        "# TYPE morse_chat_rooms gauge\n\
+         morse_chat_rooms {}\n\
+         # TYPE morse_chat_connections gauge\n\
+         morse_chat_connections {}\n\
+         # TYPE morse_chat_messages_relayed_total counter\n\
+         morse_chat_messages_relayed_total {}\n\
+         # TYPE morse_chat_connections_rejected_total counter\n\
+         morse_chat_connections_rejected_total {}\n\
+         # TYPE morse_chat_bytes_relayed_total counter\n\
+         morse_chat_bytes_relayed_total {}\n\
+         # TYPE morse_chat_room_bytes_relayed_total counter\n",
+        rooms,
+        connections,
+        metrics.messages_relayed.load(Ordering::Relaxed),
+        metrics.connections_rejected.load(Ordering::Relaxed),
+        metrics.bytes_relayed.load(Ordering::Relaxed),
+    );
+    // room names only consist of characters that are valid in label values
+    for (name, bytes) in app_data.room_bytes_relayed().await {
+        body += &format!(
+            "morse_chat_room_bytes_relayed_total{{room=\"{}\"}} {}\n",
+            name, bytes
+        );
+    }
+    Response::builder()
+        .with_status(Status::OK)
+        .with_header("content-type", "text/plain; version=0.0.4; charset=utf-8")
+        .with_body(body)
 }
 
 #[tracing::instrument(skip(app_data))]
@@ -215,129 +1128,3585 @@ async fn handle_new_room(app_data: SharedAppData) -> Response {
         .take(6)
         .map(char::from)
         .collect();
-    let mut data = app_data.lock().await;
-    if data.rooms.len() >= MAX_ROOM_NUMBER {
+    create_room(app_data, name, None).await
+}
+
+async fn handle_new_named_room(req: &Request, app_data: SharedAppData) -> Response {
+    let (name, password) = match req.json::<NewRoom>() {
+        Ok(NewRoom { name, password }) => (name, password.filter(|p| !p.is_empty())),
+        Err(err) => {
+            debug!(%err, "invalid room creation request");
+            return room_denied(Status::BadRequest, "Expected a JSON body with a room name.");
+        }
+    };
+    if !is_valid_room_name(&name) {
+        return room_denied(Status::BadRequest, "Invalid room name.");
+    }
+    create_room(app_data, name, password).await
+}
+
+async fn create_room(app_data: SharedAppData, name: String, password: Option<String>) -> Response {
+    let mut rooms = app_data.rooms.lock().await;
+    if rooms.contains_key(&name) {
+        info!(name, "room already exists. creation denied.");
+        room_denied(Status::Conflict, "Room already exists.")
+    } else if rooms.len() >= app_data.config.max_rooms {
         warn!("maximum number of rooms reached. creation denied.");
-        Response::builder()
-            .with_status(Status::Forbidden)
-            .as_json()
-            .with_body("{ \"status\": 1, \"message\": \"Rooms at capacity.\"}")
+        room_denied(Status::Forbidden, "Rooms at capacity.")
     } else {
-        data.rooms.insert(name.clone(), RoomData::new());
+        let room = RoomData::with_options(RoomOptions {
+            password,
+            ..RoomOptions::from_config(&app_data.config)
+        });
+        rooms.insert(name.clone(), room.spawn_shared(&name, &app_data));
         info!(name, "room created.");
         Response::builder()
             .with_status(Status::OK)
-            .as_json()
-            .with_body(format!("{{ \"status\": 0, \"name\": {:?}}}", name))
+            .with_json(&GenRoom::Created { status: 0, name })
     }
 }
 
-#[tracing::instrument(skip(app_data, request, stream))]
-async fn handle_new_ws(request: &Request, mut stream: TcpStream, app_data: SharedAppData) {
-    let (response, room_name) = if let Some(res) = try_upgrade_to_ws(request) {
-        info!("successfully upgraded to websocket.");
-        res
+async fn handle_delete_room(req: &Request, name: &str, app_data: &AppData) -> Response {
+    let room = if let Some(room) = app_data.lock_room(name).await {
+        room
     } else {
-        info!("failed to upgrade to websocket.");
-        let _ = Response::builder()
-            .with_status(Status::BadRequest)
-            .with_body(Vec::new())
-            .try_write_to(&mut stream)
-            .await;
-        return;
+        let message = format!("no room with name {} found", name);
+        return error_response(req, Status::NotFound, &message);
     };
-    let mut data = app_data.lock().await;
-    let room = if let Some(room) = data.rooms.get_mut(&room_name) {
+    if room.options.permanent {
+        error_response(req, Status::Forbidden, "room can't be deleted")
+    } else if !room.sockets.is_empty() {
+        error_response(req, Status::Conflict, "room still has members")
+    } else {
+        app_data.remove_room(name, room).await;
+        info!(name, "room deleted.");
+        Response::builder()
+            .with_status(Status::NoContent)
+            .with_body(Vec::new())
+    }
+}
+
+/// Disconnects all members of a room and removes it, unless it is permanent.
+/// Only admins may do this, see [Config::admin_token].
+async fn handle_close_room(req: &Request, name: &str, app_data: &AppData) -> Response {
+    if let Err(response) = authorize_admin(req, &app_data.config) {
+        return response;
+    }
+    let mut room = if let Some(room) = app_data.lock_room(name).await {
         room
     } else {
-        info!("tried to join non-existent room. answering with 404.");
-        let _ = Response::builder()
-            .with_status(Status::NotFound)
-            .with_body(format!("no room with name {} found.", room_name))
-            .try_write_to(&mut stream)
-            .await;
-        return;
+        let message = format!("no room with name {} found", name);
+        return error_response(req, Status::NotFound, &message);
     };
-
-    if let Err(e) = response.try_write_to(&mut stream).await {
-        debug!(?e, "error writing response to stream.");
-        return;
+    let mut members = Vec::with_capacity(room.sockets.len());
+    for (&id, member) in &room.sockets {
+        // a member that doesn't read loses the connection without a Close
+        // frame once it is dropped, see [WebSocket::try_close]
+        if let Err(error) = member
+            .socket
+            .try_close(CLOSE_GOING_AWAY, "room closed by admin")
+        {
+            debug!(error, id, room_name = name, "error closing member socket.");
+        }
+        members.push((id, RemovalReason::RoomClosed));
     }
-
-    let mut rng = rand::thread_rng();
-
-    let id = rng.gen();
-    let socket = WebSocket::new(stream);
-    room.sockets.insert(id, socket);
-    room.is_deletable = true;
+    let disconnected = members.len();
+    remove_members(name, &mut room, members, app_data);
+    if !room.options.permanent {
+        app_data.remove_room(name, room).await;
+    }
+    info!(name, disconnected, "room closed.");
+    Response::builder()
+        .with_status(Status::OK)
+        .with_json(&ClosedRoom { disconnected })
 }
 
-#[tracing::instrument]
-fn try_upgrade_to_ws(request: &Request) -> Option<(Response, String)> {
-    if !fulfills_ws_requirements(request) {
-        debug!("request does not fulfill ws requirements.");
-        return None;
+/// Sends a [ControlMessage::System] to the members of all rooms. Members
+/// whose send queue is full miss it.
+async fn handle_announce(req: &Request, app_data: &AppData) -> Response {
+    if let Err(response) = authorize_admin(req, &app_data.config) {
+        return response;
     }
+    let message = match req.json::<Announcement>() {
+        Ok(Announcement { message }) if !message.trim().is_empty() => message,
+        _ => {
+            let message = "expected a JSON body with a message";
+            return error_response(req, Status::BadRequest, message);
+        }
+    };
+    // the map isn't held while waiting on busy rooms
+    let rooms = app_data
+        .rooms
+        .lock()
+        .await
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let announcement = ControlMessage::System { message };
+    let mut recipients = 0;
+    for room in rooms {
+        recipients += room.lock().await.broadcast(&announcement);
+    }
+    info!(recipients, "announcement sent.");
+    Response::builder()
+        .with_status(Status::OK)
+        .with_json(&AnnouncementSent { recipients })
+}
 
-    let (_, room) = get_query_params(request.path()).find(|(key, _)| *key == "room")?;
-
-    // upgrade to websocket
-    let nonce = request
-        .headers()
-        .get(&HeaderName::from_str("sec-websocket-key"))?;
-    let hash = get_websocket_accept_hash(nonce);
-    let resp = Response::builder()
-        .with_status(Status::SwitchingProtocols)
-        .with_header("connection", "Upgrade")
-        .with_header("upgrade", "websocket")
-        .with_header("sec-websocket-accept", hash)
-        .with_body(Vec::new());
-    Some((resp, room.to_owned()))
+/// Checks that `req` carries the admin token as bearer token. Without a
+/// configured token, the admin api is disabled.
+fn authorize_admin(req: &Request, config: &Config) -> Result<(), Response> {
+    let token = if let Some(token) = &config.admin_token {
+        token
+    } else {
+        return Err(error_response(
+            req,
+            Status::Forbidden,
+            "admin api is disabled",
+        ));
+    };
+    let is_admin = req
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| is_same_secret(given.as_bytes(), token.as_bytes()));
+    if is_admin {
+        Ok(())
+    } else {
+        Err(error_response(
+            req,
+            Status::Unauthorized,
+            "invalid admin token",
+        ))
+    }
 }
 
-fn get_websocket_accept_hash(nonce: &str) -> String {
-    let concat = String::from(nonce) + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-    let mut hasher = Sha1::new();
-    hasher.update(concat);
-    let result = hasher.finalize();
-    base64::encode(result.as_slice())
+/// Compares two secrets in time that only depends on their lengths, so the
+/// token can't be guessed byte by byte.
+fn is_same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
-fn fulfills_ws_requirements(req: &Request) -> bool {
-    req.headers()
-        .get(&HeaderName::from_str("connection"))
-        .map(|v| v.to_ascii_lowercase() == "upgrade")
-        .and_then(|has_conn| {
-            Some(
-                has_conn
-                    && req
-                        .headers()
-                        .get(&HeaderName::from_str("upgrade"))?
-                        .to_ascii_lowercase()
-                        == "websocket",
-            )
-        })
-        .map(|prev| {
-            prev && req
-                .headers()
-                .get(&HeaderName::from_str("sec-websocket-key"))
-                .is_some()
-        })
-        .unwrap_or(false)
+fn room_denied(status: Status, message: &'static str) -> Response {
+    Response::builder()
+        .with_status(status)
+        .with_json(&GenRoom::Denied { status: 1, message })
 }
 
-fn get_query_params(string: &str) -> impl Iterator<Item = (&str, &str)> {
-    string
-        .split(&['?', '&'])
-        .skip(1)
-        .flat_map(|pair| pair.split_once('='))
+/// Room names end up in `/chat?room=` links, so they are restricted to
+/// characters that don't need escaping there.
+fn is_valid_room_name(name: &str) -> bool {
+    (1..=MAX_ROOM_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
 }
 
-impl RoomData {
-    pub fn new() -> Self {
-        Self {
-            sockets: HashMap::new(),
+#[tracing::instrument(skip(app_data, request, stream, permit))]
+async fn handle_new_ws<S: Io + 'static>(
+    request: &Request,
+    mut stream: S,
+    addr: SocketAddr,
+    app_data: SharedAppData,
+    permit: ConnectionPermit,
+) {
+    let upgrade = match try_upgrade_to_ws(request, &app_data.config) {
+        Ok(upgrade) => {
+            info!(
+                protocol = upgrade.protocol,
+                "successfully upgraded to websocket."
+            );
+            upgrade
+        }
+        Err(response) => {
+            info!("failed to upgrade to websocket.");
+            app_data
+                .metrics
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            let _ = response.try_write_to(&mut stream).await;
+            return;
+        }
+    };
+    let room_name = upgrade.room;
+    // authorized before the room is locked, as it may take a while
+    let auth = match app_data.authorizer.authorize(request, &room_name).await {
+        Ok(auth) => auth,
+        Err(e) => {
+            info!(
+                status = e.status.as_str(),
+                e.message, "join not authorized."
+            );
+            app_data
+                .metrics
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            let _ = error_response(request, e.status, &e.message)
+                .try_write_to(&mut stream)
+                .await;
+            return;
+        }
+    };
+    // admission is checked before the handshake response, so a client that
+    // can't join gets an HTTP error. The room isn't locked while writing to
+    // the client, which may stall for up to the write timeout.
+    let (activity, tentative_nick) = {
+        let room = if let Some(room) = app_data.lock_room(&room_name).await {
+            room
+        } else {
+            info!("tried to join non-existent room. answering with 404.");
+            app_data
+                .metrics
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            let message = format!("no room with name {} found", room_name);
+            let _ = error_response(request, Status::NotFound, &message)
+                .try_write_to(&mut stream)
+                .await;
+            return;
+        };
+        if let Err(reason) = room.admits(upgrade.password.as_deref()) {
+            info!(reason, "room denied joining.");
+            app_data
+                .metrics
+                .connections_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            let _ = error_response(request, Status::Forbidden, reason)
+                .try_write_to(&mut stream)
+                .await;
+            return;
+        }
+        let nick = upgrade.nick.clone().map(|nick| room.unique_nick(nick));
+        (Arc::clone(&room.activity), nick)
+    };
+
+    let write_timeout = app_data.config.write_timeout;
+    if let Err(e) =
+        with_write_timeout(write_timeout, upgrade.response.try_write_to(&mut stream)).await
+    {
+        debug!(?e, "error writing response to stream.");
+        return;
+    }
+
+    let restored = upgrade.token.and_then(|token| {
+        let restored = app_data.redeem_reconnect_token(&token, &room_name)?;
+        Some((token, restored))
+    });
+    let is_restored = restored.is_some();
+    let (id, nick, token) = match restored {
+        Some((token, restored)) => (restored.id, restored.nick, token),
+        None => {
+            let id = app_data.next_connection_id();
+            let token = app_data.issue_reconnect_token(&room_name, id, tentative_nick.clone());
+            (id, tentative_nick, token)
+        }
+    };
+    // the welcome is written before the stream task takes over the stream, so
+    // a client that reset the connection right after the handshake usually
+    // never joins the room
+    let welcome = ControlMessage::Welcome {
+        id,
+        token: token.clone(),
+    };
+    if let Err(e) = with_write_timeout(write_timeout, write_control_to(&mut stream, &welcome)).await
+    {
+        debug!(?e, "error sending welcome message.");
+        app_data.release_reconnect_token(&token);
+        return;
+    }
+    let config = websockets::Config {
+        protocol: upgrade.protocol,
+        extensions: upgrade.extensions,
+        peer_addr: Some(addr),
+        write_timeout,
+        idle_timeout: app_data.config.idle_timeout,
+        max_message_size: app_data.config.max_message_size,
+        // tags everything the stream task logs with the connection it serves
+        span: Some(tracing::info_span!("websocket", room_name, id, peer_addr = %addr)),
+        received_notify: Some(activity),
+        ..Default::default()
+    };
+    let socket = WebSocket::with_config(stream, config);
+
+    // the room may have been removed or filled up while it wasn't locked
+    let mut room = if let Some(room) = app_data.lock_room(&room_name).await {
+        room
+    } else {
+        info!("room was removed while joining.");
+        app_data
+            .metrics
+            .connections_rejected
+            .fetch_add(1, Ordering::Relaxed);
+        let _ = socket.try_close(CLOSE_GOING_AWAY, "room closed");
+        app_data.release_reconnect_token(&token);
+        return;
+    };
+    if let Err(reason) = room.admits(upgrade.password.as_deref()) {
+        info!(reason, "room denied joining after the handshake.");
+        app_data
+            .metrics
+            .connections_rejected
+            .fetch_add(1, Ordering::Relaxed);
+        let _ = socket.try_close(CLOSE_POLICY_VIOLATION, reason);
+        app_data.release_reconnect_token(&token);
+        return;
+    }
+    // a restored member's old connection may not have been noticed as dropped
+    // yet. New members have a fresh id, which is never in the room.
+    let is_replacing = if let Some(old) = room.sockets.remove(&id) {
+        debug!(id, "replacing connection of rejoining member.");
+        task::spawn(old.socket.shutdown());
+        true
+    } else {
+        false
+    };
+    // someone may have taken the nick since it was picked, or since the
+    // restored member left. A replaced connection was removed above, so its
+    // member keeps the nick.
+    let requested = if is_restored {
+        nick.clone()
+    } else {
+        upgrade.nick
+    };
+    let unique = requested.map(|nick| room.unique_nick(nick));
+    if unique != nick {
+        app_data.set_reconnect_nick(&token, unique.clone());
+    }
+    let nick = unique;
+    // the history goes out as one message, which always fits into the empty
+    // send queue, and the room stays locked, so no live message overtakes it
+    if let Some(history) = room.history_message() {
+        if let Err(e) = socket.try_send_now(Arc::new(history.to_message())) {
+            debug!(?e, "error replaying room history.");
+            app_data.release_reconnect_token(&token);
+            return;
+        }
+    }
+    let mut member = Member::new(socket, &app_data.config);
+    member.connection_permit = Some(permit);
+    member.nick = nick;
+    member.reconnect_token = Some(token);
+    member.auth = auth;
+    info!(
+        id,
+        nick = member.nick.as_deref(),
+        user_id = member.auth.user_id.as_deref(),
+        room_name,
+        "member joined."
+    );
+    if app_data.config.member_events && !is_replacing {
+        let nick = member.nick.clone();
+        room.broadcast(&ControlMessage::Join(MemberInfo { id, nick }));
+    }
+    room.sockets.insert(id, member);
+    room.is_deletable = true;
+    if app_data.config.broadcast_members {
+        room.broadcast_members();
+    }
+}
+
+/// Limits `write` to `limit`, see [Config::write_timeout]. Used for the writes
+/// of [handle_new_ws] before a [WebSocket] takes over the stream.
+async fn with_write_timeout(
+    limit: Option<Duration>,
+    write: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, write)
+            .await
+            .context("write timed out")?,
+        None => write.await,
+    }
+}
+
+/// Writes `control` to `stream` as a single text frame and flushes it, for
+/// before a [WebSocket] takes over the stream.
+async fn write_control_to<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    control: &ControlMessage,
+) -> anyhow::Result<()> {
+    let text = serde_json::to_string(control)?;
+    let mut frame = Vec::new();
+    Frame::builder()
+        .is_final()
+        .with_opcode(OpCode::Text)
+        .write_payload_to(text.as_bytes(), &mut frame)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Builds the handshake response for a websocket upgrade request. If the
+/// request can't be upgraded, the response to reject it with is returned
+/// instead.
+#[tracing::instrument(skip(config))]
+fn try_upgrade_to_ws(request: &Request, config: &Config) -> Result<Upgrade, Response> {
+    let bad_request = || {
+        error_response(
+            request,
+            Status::BadRequest,
+            "invalid websocket upgrade request",
+        )
+    };
+
+    if !fulfills_ws_requirements(request) {
+        debug!("request does not fulfill ws requirements.");
+        return Err(bad_request());
+    }
+
+    let version = request.header("sec-websocket-version");
+    if version.map(|v| v.trim()) != Some(WS_VERSION) {
+        debug!(?version, "unsupported websocket version.");
+        let mut response = error_response(
+            request,
+            Status::UpgradeRequired,
+            "unsupported websocket version",
+        );
+        response.set_header("sec-websocket-version", WS_VERSION);
+        return Err(response);
+    }
+
+    if !is_allowed_origin(request, &config.allowed_origins) {
+        let origin = request.header("origin");
+        debug!(?origin, "websocket origin not allowed.");
+        return Err(error_response(
+            request,
+            Status::Forbidden,
+            "websocket origin not allowed",
+        ));
+    }
+
+    // without a room, clients join the default room
+    let room = get_query_params(request.path())
+        .find(|(key, _)| *key == "room")
+        .map(|(_, room)| room)
+        .or(config.default_room.as_deref());
+    let room = if let Some(room) = room {
+        room
+    } else {
+        debug!("no room given and the default room is disabled.");
+        return Err(error_response(
+            request,
+            Status::NotFound,
+            "no room given and there is no default room",
+        ));
+    };
+
+    let nick = get_query_params(request.path())
+        .find(|(key, _)| *key == "nick")
+        .and_then(|(_, nick)| sanitize_nick(nick));
+    let password = get_query_params(request.path())
+        .find(|(key, _)| *key == "password")
+        .map(|(_, password)| password.to_owned());
+    let token = get_query_params(request.path())
+        .find(|(key, _)| *key == "token")
+        .map(|(_, token)| token.to_owned());
+
+    // upgrade to websocket
+    let nonce = request
+        .header("sec-websocket-key")
+        .ok_or_else(bad_request)?;
+    if !is_valid_websocket_key(nonce) {
+        debug!(nonce, "invalid sec-websocket-key.");
+        return Err(bad_request());
+    }
+    let hash = websockets::accept_key(nonce);
+    let protocol = request
+        .header("sec-websocket-protocol")
+        .and_then(|offered| select_subprotocol(offered, &config.subprotocols));
+    let extensions = request
+        .header("sec-websocket-extensions")
+        .map(|offered| select_extensions(offered, SUPPORTED_EXTENSIONS))
+        .unwrap_or_default();
+    let mut builder = Response::builder();
+    builder
+        .with_status(Status::SwitchingProtocols)
+        .with_header("connection", "Upgrade")
+        .with_header("upgrade", "websocket")
+        .with_header("sec-websocket-accept", hash);
+    if let Some(protocol) = &protocol {
+        builder.with_header("sec-websocket-protocol", *protocol);
+    }
+    // without any extension, the header is left out
+    if !extensions.is_empty() {
+        builder.with_header("sec-websocket-extensions", extensions.join(", "));
+    }
+    Ok(Upgrade {
+        response: builder.with_body(Vec::new()),
+        room: room.to_owned(),
+        protocol: protocol.map(String::from),
+        extensions,
+        nick,
+        password,
+        token,
+    })
+}
+
+/// Keeps the characters of a client supplied nickname that are safe to show
+/// anywhere, i.e. ASCII letters, digits, `-` and `_`, and cuts it to
+/// [MAX_NICK_LEN]. Returns `None` if nothing is left.
+fn sanitize_nick(nick: &str) -> Option<String> {
+    let nick = nick
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(MAX_NICK_LEN)
+        .collect::<String>();
+    if nick.is_empty() {
+        None
+    } else {
+        Some(nick)
+    }
+}
+
+/// Returns whether the `Origin` of `request` is one of `allowed`. Browsers
+/// always send it with websocket handshakes, so other sites can't open
+/// websockets in the name of their visitors. An empty list allows everything.
+fn is_allowed_origin(request: &Request, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let origin = request.header("origin");
+    origin.is_some_and(|origin| {
+        allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    })
+}
+
+/// Returns the first of the client's comma separated subprotocols that the
+/// server supports.
+fn select_subprotocol<'a>(offered: &'a str, supported: &[String]) -> Option<&'a str> {
+    offered
+        .split(',')
+        .map(str::trim)
+        .find(|protocol| supported.iter().any(|s| s == protocol))
+}
+
+/// Returns the names of the client's comma separated extensions that the
+/// server supports, each once and in the client's order. Extension parameters
+/// are dropped, so supported extensions run with their defaults.
+fn select_extensions(offered: &str, supported: &[&str]) -> Vec<String> {
+    let mut selected = Vec::<String>::new();
+    for extension in offered.split(',') {
+        let name = extension.split(';').next().unwrap_or_default().trim();
+        if supported.contains(&name) && !selected.iter().any(|s| s == name) {
+            selected.push(name.to_owned());
+        }
+    }
+    selected
+}
+
+/// A valid key is a base64 encoded 16 byte value (RFC 6455, section 4.1).
+fn is_valid_websocket_key(nonce: &str) -> bool {
+    base64::decode(nonce).is_ok_and(|key| key.len() == 16)
+}
+
+fn fulfills_ws_requirements(req: &Request) -> bool {
+    req.header_contains_token("connection", "upgrade")
+        && req.header_contains_token("upgrade", "websocket")
+        && req.header("sec-websocket-key").is_some()
+}
+
+fn get_query_params(string: &str) -> impl Iterator<Item = (&str, &str)> {
+    string
+        .split(&['?', '&'])
+        .skip(1)
+        .flat_map(|pair| pair.split_once('='))
+}
+
+impl ControlMessage {
+    /// Returns the control message `message` consists of, or `None` if it is
+    /// a regular chat message.
+    fn parse(message: &Message) -> Option<Self> {
+        match message {
+            // chat messages never start with a brace, so they aren't parsed
+            Message::Text(text) if text.starts_with('{') => serde_json::from_str(text).ok(),
+            _ => None,
+        }
+    }
+
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("control messages serialize"))
+    }
+}
+
+impl ReconnectToken {
+    /// Returns whether the member left longer than `grace` ago.
+    fn is_expired(&self, grace: Duration) -> bool {
+        self.left_at.is_some_and(|left| left.elapsed() > grace)
+    }
+}
+
+impl AppData {
+    fn new(config: Config) -> Self {
+        Self::with_authorizer(config, Box::new(AllowAll))
+    }
+
+    /// Like [AppData::new], but joining rooms is decided by `authorizer`
+    /// instead of letting everyone in.
+    fn with_authorizer(config: Config, authorizer: Box<dyn JoinAuthorizer>) -> Self {
+        Self {
+            connection_permits: Arc::new(Semaphore::new(config.max_connections)),
+            ip_connections: IpConnectionLimit::new(config.max_connections_per_ip),
+            config,
+            metrics: Metrics::default(),
+            rooms: Mutex::default(),
+            connection_id_counter: AtomicUsize::default(),
+            router: routes(),
+            content_types: ContentTypes::default(),
+            reconnect_tokens: Default::default(),
+            authorizer,
+        }
+    }
+
+    /// Counts a new connection from `ip` against the connection limits.
+    /// Returns the status to reject the connection with if a limit is reached.
+    /// Behind a trusted proxy, `ip` is the proxy's address, so the connection
+    /// is only counted per address once its first request names the client,
+    /// see [AppData::assign_client_ip].
+    fn try_acquire_connection(&self, ip: IpAddr) -> Result<ConnectionPermit, Status> {
+        let per_ip = if self.config.trust_proxy {
+            None
+        } else {
+            let guard = self
+                .ip_connections
+                .try_acquire(ip)
+                .ok_or(Status::TooManyRequests)?;
+            Some(guard)
+        };
+        let global = Arc::clone(&self.connection_permits)
+            .try_acquire_owned()
+            .map_err(|_| Status::ServiceUnavailable)?;
+        Ok(ConnectionPermit {
+            _global: global,
+            per_ip,
+        })
+    }
+
+    /// Counts the connection of `permit` against the client address `ip`
+    /// instead of the address it was counted against so far. A proxy may
+    /// forward requests of different clients over the same connection.
+    fn assign_client_ip(&self, permit: &mut ConnectionPermit, ip: IpAddr) -> Result<(), Status> {
+        if permit.per_ip.as_ref().is_some_and(|guard| guard.ip() == ip) {
+            return Ok(());
+        }
+        permit.per_ip = None;
+        let guard = self
+            .ip_connections
+            .try_acquire(ip)
+            .ok_or(Status::TooManyRequests)?;
+        permit.per_ip = Some(guard);
+        Ok(())
+    }
+
+    /// Returns an id that is unique among all connections of this process.
+    fn next_connection_id(&self) -> usize {
+        self.connection_id_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns a new token with which the member `id` can rejoin `room` with
+    /// the same id and nick after it left.
+    fn issue_reconnect_token(&self, room: &str, id: usize, nick: Option<String>) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let restores = ReconnectToken {
+            room: room.to_owned(),
+            id,
+            nick,
+            left_at: None,
+        };
+        let mut tokens = self.reconnect_tokens.lock().unwrap();
+        tokens.insert(token.clone(), restores);
+        token
+    }
+
+    /// Changes the nick `token` restores, e.g. after the member's nick had to
+    /// be made unique.
+    fn set_reconnect_nick(&self, token: &str, nick: Option<String>) {
+        if let Some(restores) = self.reconnect_tokens.lock().unwrap().get_mut(token) {
+            restores.nick = nick;
+        }
+    }
+
+    /// Starts the grace period of a token once its member left.
+    fn release_reconnect_token(&self, token: &str) {
+        if let Some(restores) = self.reconnect_tokens.lock().unwrap().get_mut(token) {
+            restores.left_at = Some(Instant::now());
+        }
+    }
+
+    /// Returns the identity `token` restores if it was issued for `room` and
+    /// hasn't expired. The token stays valid for the next reconnect.
+    fn redeem_reconnect_token(&self, token: &str, room: &str) -> Option<ReconnectToken> {
+        let grace = self.config.reconnect_grace;
+        let mut tokens = self.reconnect_tokens.lock().unwrap();
+        let restores = tokens.get_mut(token)?;
+        if restores.room != room || restores.is_expired(grace) {
+            return None;
+        }
+        restores.left_at = None;
+        Some(restores.clone())
+    }
+
+    /// Forgets tokens whose member left longer than the grace period ago.
+    fn remove_expired_reconnect_tokens(&self) {
+        let grace = self.config.reconnect_grace;
+        self.reconnect_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, restores| !restores.is_expired(grace));
+    }
+
+    /// Returns the number of rooms and the number of connections across all
+    /// rooms.
+    async fn count_connections(&self) -> (usize, usize) {
+        // copy the rooms out so the map isn't locked while counting the members
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut connections = 0;
+        for room in &rooms {
+            connections += room.lock().await.sockets.len();
+        }
+        (rooms.len(), connections)
+    }
+
+    /// Returns the bytes relayed in each room, ordered by room name.
+    async fn room_bytes_relayed(&self) -> Vec<(String, u64)> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .await
+            .iter()
+            .map(|(name, room)| (name.clone(), Arc::clone(room)))
+            .collect::<Vec<_>>();
+        rooms.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut bytes = Vec::with_capacity(rooms.len());
+        for (name, room) in rooms {
+            bytes.push((name, room.lock().await.bytes_relayed));
+        }
+        bytes
+    }
+
+    /// Locks the room with the given name. The map isn't held while waiting
+    /// on a busy room, so a room removed meanwhile counts as not found.
+    async fn lock_room(&self, name: &str) -> Option<OwnedMutexGuard<RoomData>> {
+        let room = Arc::clone(self.rooms.lock().await.get(name)?);
+        let room = room.lock_owned().await;
+        if room.is_removed {
+            None
+        } else {
+            Some(room)
+        }
+    }
+
+    /// Takes the locked room `room` out of the map. The room is unlocked before
+    /// the map is locked, so this never waits on the map while holding a room.
+    async fn remove_room(&self, name: &str, mut room: OwnedMutexGuard<RoomData>) {
+        room.is_removed = true;
+        let removed = Arc::clone(OwnedMutexGuard::mutex(&room));
+        drop(room);
+        let mut rooms = self.rooms.lock().await;
+        if rooms
+            .get(name)
+            .is_some_and(|room| Arc::ptr_eq(room, &removed))
+        {
+            rooms.remove(name);
+        }
+    }
+}
+
+impl Default for AppData {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl RoomOptions {
+    /// The options of a room created with the server wide defaults.
+    fn from_config(config: &Config) -> Self {
+        Self {
+            max_members: config.max_room_members,
+            permanent: false,
+            history_size: config.history_size,
+            password: None,
+        }
+    }
+}
+
+impl Default for RoomOptions {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+impl RoomData {
+    pub fn with_options(options: RoomOptions) -> Self {
+        Self {
+            sockets: HashMap::new(),
             is_deletable: false,
+            is_removed: false,
+            options,
+            created_at: Instant::now(),
+            history: VecDeque::new(),
+            bytes_relayed: 0,
+            activity: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Checks whether a new member with the given password may join. Returns
+    /// the reason if not.
+    fn admits(&self, password: Option<&str>) -> Result<(), &'static str> {
+        if let Some(expected) = &self.options.password {
+            let is_expected = password
+                .is_some_and(|password| is_same_secret(password.as_bytes(), expected.as_bytes()));
+            if !is_expected {
+                return Err("wrong room password.");
+            }
+        }
+        if self
+            .options
+            .max_members
+            .is_some_and(|max| self.sockets.len() >= max)
+        {
+            return Err("room is full.");
+        }
+        Ok(())
+    }
+
+    /// Returns the [ControlMessage::History] of the room, if it has any. Only
+    /// text messages are replayed, as json can't carry binary ones.
+    fn history_message(&self) -> Option<ControlMessage> {
+        let messages = self
+            .history
+            .iter()
+            .filter_map(|message| match &**message {
+                Message::Text(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if messages.is_empty() {
+            None
+        } else {
+            Some(ControlMessage::History { messages })
+        }
+    }
+
+    /// Returns the members of the room, ordered by id.
+    fn member_list(&self) -> Vec<MemberInfo> {
+        let mut members = self
+            .sockets
+            .iter()
+            .map(|(&id, member)| MemberInfo {
+                id,
+                nick: member.nick.clone(),
+            })
+            .collect::<Vec<_>>();
+        members.sort_unstable_by_key(|member| member.id);
+        members
+    }
+
+    /// Returns `nick`, or if a member already goes by it, `nick` with the
+    /// lowest numeric suffix that makes it unique, e.g. `bob-2`.
+    fn unique_nick(&self, nick: String) -> String {
+        let is_taken = |nick: &str| {
+            self.sockets
+                .values()
+                .any(|member| member.nick.as_deref() == Some(nick))
+        };
+        if !is_taken(&nick) {
+            return nick;
+        }
+        (2..)
+            .map(|n| {
+                let suffix = format!("-{}", n);
+                let base_len = nick.len().min(MAX_NICK_LEN - suffix.len());
+                format!("{}{}", &nick[..base_len], suffix)
+            })
+            .find(|candidate| !is_taken(candidate))
+            .unwrap()
+    }
+
+    /// Sends `control` to every member. Members whose send queue is full miss
+    /// it. Returns the number of members it was queued for.
+    fn broadcast(&self, control: &ControlMessage) -> usize {
+        let message = Arc::new(control.to_message());
+        let mut sent = 0;
+        for (&id, member) in &self.sockets {
+            match member.socket.try_send_now(Arc::clone(&message)) {
+                Ok(()) => sent += 1,
+                Err(error) => debug!(?error, id, "error broadcasting control message."),
+            }
+        }
+        sent
+    }
+
+    /// Sends the current member list to every member, see
+    /// [RoomData::broadcast].
+    fn broadcast_members(&self) {
+        self.broadcast(&ControlMessage::Members {
+            members: self.member_list(),
+        });
+    }
+
+    /// Sends a Ping to every member, see [Config::ping_interval]. Members whose
+    /// send queue is full are skipped, they are busy anyway.
+    fn ping_members(&self) {
+        let ping = Arc::new(Message::Ping(Vec::new()));
+        for (&id, member) in &self.sockets {
+            if let Err(error) = member.socket.try_send_now(Arc::clone(&ping)) {
+                debug!(?error, id, "error sending ping.");
+            }
+        }
+    }
+
+    /// Sends a direct message to the member with id `to` only. If that member
+    /// is gone or doesn't keep up, the sender gets an error message instead.
+    /// Direct messages are not kept in the history.
+    fn send_direct(&self, from: usize, to: usize, text: String) {
+        let message = ControlMessage::Direct {
+            to: None,
+            from: Some(from),
+            text,
+        };
+        let delivered = self.sockets.get(&to).is_some_and(|member| {
+            member
+                .socket
+                .try_send_now(Arc::new(message.to_message()))
+                .is_ok()
+        });
+        if delivered {
+            return;
+        }
+        debug!(from, to, "couldn't deliver direct message.");
+        let error = ControlMessage::Error {
+            message: format!("couldn't deliver message to member {}", to),
+        };
+        if let Some(sender) = self.sockets.get(&from) {
+            if let Err(error) = sender.socket.try_send_now(Arc::new(error.to_message())) {
+                debug!(?error, from, "error sending error message.");
+            }
+        }
+    }
+
+    /// Makes the room lockable and spawns its relay task.
+    pub fn spawn_shared(self, name: &str, app_data: &SharedAppData) -> SharedRoomData {
+        let room = Arc::new(Mutex::new(self));
+        task::spawn(room_relay_task(
+            name.to_owned(),
+            Arc::downgrade(&room),
+            Arc::clone(app_data),
+        ));
+        room
+    }
+}
+
+impl Default for RoomData {
+    fn default() -> Self {
+        Self::with_options(RoomOptions::default())
+    }
+}
+
+impl Member {
+    pub fn new(socket: WebSocket, config: &Config) -> Self {
+        Self {
+            socket,
+            rate_limiter: TokenBucket::new(config.message_rate, config.message_burst),
+            rate_violations: 0,
+            max_rate_violations: config.max_rate_violations,
+            full_send_cycles: 0,
+            max_full_send_cycles: config.max_full_send_cycles,
+            send_policy: config.send_policy,
+            outbox: VecDeque::new(),
+            nick: None,
+            connection_permit: None,
+            reconnect_token: None,
+            auth: AuthInfo::default(),
+        }
+    }
+
+    /// Returns whether the member's next message has to be dropped and counts
+    /// it as a violation if so. A message that passes resets the count, so
+    /// only violations in a row add up.
+    fn is_rate_limited(&mut self) -> bool {
+        let is_limited = !self.rate_limiter.try_take();
+        if is_limited {
+            self.rate_violations += 1;
+        } else {
+            self.rate_violations = 0;
+        }
+        is_limited
+    }
+
+    fn exceeds_rate_violations(&self) -> bool {
+        self.max_rate_violations
+            .is_some_and(|max| self.rate_violations > max)
+    }
+
+    /// Sends a message from another member according to the [SendPolicy].
+    /// Returns what reached the send queue, or why the member has to be
+    /// removed. Under [SendPolicy::DropOldest], that may be older messages
+    /// held back before instead of this one.
+    async fn relay(&mut self, message: Arc<Message>) -> Result<Queued, RemovalReason> {
+        let queued = Queued::one(&message);
+        let result = match self.send_policy {
+            SendPolicy::Block => {
+                return match self.socket.send_shared(message).await {
+                    Ok(()) => Ok(queued),
+                    Err(_) => Err(RemovalReason::Error(MessageError::SendFailed)),
+                };
+            }
+            SendPolicy::DropOldest => {
+                if self.outbox.len() >= MAX_OUTBOX_LEN {
+                    trace!("dropping oldest queued message.");
+                    self.outbox.pop_front();
+                }
+                self.outbox.push_back(message);
+                return self.flush_outbox();
+            }
+            SendPolicy::DropNewest | SendPolicy::Disconnect => self.socket.try_send_now(message),
+        };
+        match result {
+            Ok(()) => Ok(queued),
+            Err(TrySendError::Full(_)) if self.send_policy == SendPolicy::Disconnect => {
+                Err(RemovalReason::SlowConsumer)
+            }
+            Err(TrySendError::Full(_)) => Ok(Queued::default()),
+            Err(TrySendError::Closed(_)) => Err(RemovalReason::Error(MessageError::SendFailed)),
+        }
+    }
+
+    /// Moves messages from the outbox to the send queue until it is full.
+    /// Returns what it moved.
+    fn flush_outbox(&mut self) -> Result<Queued, RemovalReason> {
+        let mut queued = Queued::default();
+        while let Some(message) = self.outbox.pop_front() {
+            let one = Queued::one(&message);
+            match self.socket.try_send_now(message) {
+                Ok(()) => {
+                    queued.messages += one.messages;
+                    queued.bytes += one.bytes;
+                }
+                Err(TrySendError::Full(message)) => {
+                    self.outbox.push_front(message);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    return Err(RemovalReason::Error(MessageError::SendFailed));
+                }
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Counts another relay cycle and returns whether the member's send queue
+    /// has been full for more cycles in a row than allowed.
+    fn is_slow_consumer(&mut self) -> bool {
+        if self.socket.is_send_queue_full() {
+            self.full_send_cycles += 1;
+        } else {
+            self.full_send_cycles = 0;
+        }
+        self.max_full_send_cycles
+            .is_some_and(|max| self.full_send_cycles > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt};
+
+    use backend::auth::AuthError;
+
+    use super::*;
+
+    /// Returns the server and client end of a local tcp connection.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    /// Returns a server side [WebSocket] and the raw client end of its stream.
+    async fn socket_pair() -> (WebSocket, TcpStream) {
+        let (server, client) = tcp_pair().await;
+        (WebSocket::new(server), client)
+    }
+
+    /// Like [socket_pair], but the socket wakes the relay task of `room` like
+    /// the sockets of members that joined through [handle_new_ws].
+    async fn room_socket_pair(room: &RoomData) -> (WebSocket, TcpStream) {
+        let (server, client) = tcp_pair().await;
+        let config = websockets::Config {
+            received_notify: Some(Arc::clone(&room.activity)),
+            ..Default::default()
+        };
+        (WebSocket::with_config(server, config), client)
+    }
+
+    /// Relays the messages of `room` until `done` holds and a relay pass after
+    /// that found nothing to do, so everything its members had sent by then
+    /// is handled. Fails if that takes longer than a second.
+    async fn relay_until(
+        room: &mut RoomData,
+        app_data: &AppData,
+        done: impl Fn(&RoomData) -> bool,
+    ) {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        loop {
+            let is_done = done(room);
+            let is_active = relay_room_messages("room", room, app_data).await;
+            if is_done && !is_active {
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline, "relaying timed out");
+            tokio::time::sleep(Duration::from_millis(1)).await;
         }
     }
+
+    /// Returns how many messages member `id` of `room` has sent so far.
+    fn received(room: &RoomData, id: usize) -> u64 {
+        room.sockets[&id].socket.stats().messages_received
+    }
+
+    /// Reads the next message, which has to be a short control message.
+    async fn read_control<R: AsyncRead + Unpin>(client: &mut R) -> Option<ControlMessage> {
+        let mut head = [0; 2];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut head))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(head[0], 0x81);
+        let mut payload = vec![0; head[1] as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        ControlMessage::parse(&Message::Text(String::from_utf8(payload).unwrap()))
+    }
+
+    /// Reads the welcome message every member gets first and returns its id
+    /// and reconnection token.
+    async fn read_welcome<R: AsyncRead + Unpin>(client: &mut R) -> (usize, String) {
+        match read_control(client).await {
+            Some(ControlMessage::Welcome { id, token }) => (id, token),
+            other => panic!("expected welcome message, got {:?}", other),
+        }
+    }
+
+    /// Encodes a final, masked text frame the way a client would send it.
+    fn client_text_frame(text: &str) -> Vec<u8> {
+        let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    /// The example key from RFC 6455, used by all test handshakes.
+    const TEST_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    /// Raw upgrade request for `path` with the key `key` and `extra_headers`,
+    /// which have to end with a line break each.
+    fn upgrade_request(path: &str, key: &str, extra_headers: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             {}\r\n",
+            path, key, extra_headers
+        )
+    }
+
+    /// Opens a websocket to `path` through [handle_connection] like a browser
+    /// would and returns the client end with the handshake response read,
+    /// which has to carry the right `Sec-WebSocket-Accept` value.
+    async fn connect(app_data: &SharedAppData, path: &str) -> BufReader<tokio::io::DuplexStream> {
+        let (server, client) = tokio::io::duplex(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+        task::spawn(handle_connection(
+            server,
+            addr,
+            Arc::clone(app_data),
+            permit,
+        ));
+        let mut client = BufReader::new(client);
+        let request = upgrade_request(path, TEST_KEY, "Sec-WebSocket-Version: 13\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            client.read_line(&mut head).await.unwrap();
+        }
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{}",
+            head
+        );
+        let accept = format!(
+            "sec-websocket-accept: {}\r\n",
+            websockets::accept_key(TEST_KEY)
+        );
+        assert!(head.contains(&accept), "{}", head);
+        client
+    }
+
+    /// A client that talks to the whole server through an in-memory pipe, see
+    /// [connect], and exchanges frames with the room's relay task like a
+    /// browser would.
+    struct TestClient {
+        id: usize,
+        stream: BufReader<tokio::io::DuplexStream>,
+    }
+
+    impl TestClient {
+        /// Opens a websocket to `path` and reads the welcome message.
+        async fn connect(app_data: &SharedAppData, path: &str) -> Self {
+            let mut stream = connect(app_data, path).await;
+            let (id, _) = read_welcome(&mut stream).await;
+            Self { id, stream }
+        }
+
+        async fn send_text(&mut self, text: &str) {
+            self.stream
+                .write_all(&client_text_frame(text))
+                .await
+                .unwrap();
+        }
+
+        async fn next_frame(&mut self) -> Frame {
+            let frame = Frame::try_parse_from(&mut self.stream);
+            tokio::time::timeout(Duration::from_secs(1), frame)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    fn app_data_with_rooms(names: &[&str]) -> SharedAppData {
+        let app_data = Arc::new(AppData::default());
+        let rooms = names
+            .iter()
+            .map(|&name| {
+                let room = RoomData::default().spawn_shared(name, &app_data);
+                (name.to_owned(), room)
+            })
+            .collect();
+        *app_data.rooms.try_lock().unwrap() = rooms;
+        app_data
+    }
+
+    #[tokio::test]
+    async fn test_clients_chat_end_to_end() {
+        let app_data = Arc::new(AppData::default());
+        add_default_room(&app_data).await;
+        let path = format!("/ws?room={}", config::DEFAULT_ROOM_NAME);
+        let mut alice = TestClient::connect(&app_data, &path).await;
+        let mut bob = TestClient::connect(&app_data, &path).await;
+        assert_ne!(alice.id, bob.id);
+
+        alice.send_text("dah-dit").await;
+        let frame = bob.next_frame().await;
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), b"dah-dit");
+
+        bob.send_text("dit-dit-dit").await;
+        let frame = alice.next_frame().await;
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), b"dit-dit-dit");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_relay_wakes_on_message() {
+        let app_data = Arc::new(AppData::new(Config {
+            relay_idle_interval: Duration::from_secs(60),
+            ..Config::default()
+        }));
+        let room = RoomData::default().spawn_shared("a", &app_data);
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+        let mut sender = connect(&app_data, "/ws?room=a").await;
+        let mut receiver = connect(&app_data, "/ws?room=a").await;
+        read_welcome(&mut sender).await;
+        read_welcome(&mut receiver).await;
+        // let the relay task run out of work and go to sleep
+        tokio::time::sleep(Duration::from_secs(120)).await;
+
+        let sent_at = Instant::now();
+        sender.write_all(&client_text_frame("hi")).await.unwrap();
+        let mut buf = [0; 4];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[2..], b"hi");
+        // the paused clock would have skipped the whole idle interval if the
+        // relay task hadn't been woken
+        assert!(sent_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_rooms_are_serviced_concurrently() {
+        let app_data = app_data_with_rooms(&["a", "b"]);
+        let (mut sender_client, mut receiver_client);
+        {
+            let mut room_b = app_data.lock_room("b").await.unwrap();
+            let (sender, receiver);
+            (sender, sender_client) = room_socket_pair(&room_b).await;
+            (receiver, receiver_client) = room_socket_pair(&room_b).await;
+            room_b
+                .sockets
+                .insert(0, Member::new(sender, &app_data.config));
+            room_b
+                .sockets
+                .insert(1, Member::new(receiver, &app_data.config));
+        }
+
+        // keep room a busy for the whole test
+        let _room_a = app_data.lock_room("a").await.unwrap();
+
+        sender_client
+            .write_all(&client_text_frame("hi"))
+            .await
+            .unwrap();
+        let mut buf = [0; 4];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .expect("room b was blocked by room a")
+            .unwrap();
+        assert_eq!(buf, [0x81, 0x02, b'h', b'i']);
+    }
+
+    #[tokio::test]
+    async fn test_busy_room_does_not_block_room_listing() {
+        let app_data = app_data_with_rooms(&["a", "b"]);
+        let _room_a = app_data.lock_room("a").await.unwrap();
+        let join_b = tokio::time::timeout(Duration::from_secs(1), app_data.lock_room("b")).await;
+        assert!(join_b.unwrap().is_some());
+        let listing = tokio::time::timeout(Duration::from_secs(1), app_data.rooms.lock()).await;
+        assert_eq!(listing.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_on_busy_room_releases_map() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let room_a = Arc::clone(&app_data.rooms.lock().await["a"]);
+        let mut busy = room_a.lock().await;
+        let join = task::spawn({
+            let app_data = Arc::clone(&app_data);
+            async move { app_data.lock_room("a").await.is_some() }
+        });
+        task::yield_now().await;
+
+        // the room is removed while the join waits for it
+        app_data.rooms.try_lock().unwrap().remove("a");
+        busy.is_removed = true;
+        drop(busy);
+        assert!(!join.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_remove_empty_rooms() {
+        let app_data = app_data_with_rooms(&["kept", "removed"]);
+        app_data.lock_room("removed").await.unwrap().is_deletable = true;
+        remove_empty_rooms(&app_data).await;
+        let rooms = app_data.rooms.lock().await;
+        assert!(rooms.contains_key("kept"));
+        assert!(!rooms.contains_key("removed"));
+    }
+
+    #[tokio::test]
+    async fn test_excess_messages_are_dropped() {
+        let config = Config {
+            message_rate: 0.001,
+            message_burst: 2,
+            max_rate_violations: None,
+            ..Config::default()
+        };
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let (receiver, mut receiver_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets.insert(0, Member::new(sender, &config));
+        room.sockets.insert(1, Member::new(receiver, &config));
+
+        for _ in 0..5 {
+            sender_client
+                .write_all(&client_text_frame("hi"))
+                .await
+                .unwrap();
+        }
+        relay_until(&mut room, &app_data, |room| received(room, 0) == 5).await;
+
+        let mut buf = [0; 8];
+        receiver_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x81, 0x02, b'h', b'i', 0x81, 0x02, b'h', b'i']);
+        let more = tokio::time::timeout(Duration::from_millis(50), receiver_client.read(&mut buf));
+        assert!(more.await.is_err(), "rate limited message was relayed");
+        assert_eq!(room.sockets[&0].rate_violations, 3);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_violations_disconnect() {
+        let config = Config {
+            message_rate: 0.001,
+            message_burst: 1,
+            max_rate_violations: Some(1),
+            ..Config::default()
+        };
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets.insert(0, Member::new(sender, &config));
+
+        for _ in 0..3 {
+            sender_client
+                .write_all(&client_text_frame("hi"))
+                .await
+                .unwrap();
+        }
+        relay_until(&mut room, &app_data, |room| room.sockets.is_empty()).await;
+        assert!(room.sockets.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_old_violations_are_forgiven() {
+        let config = Config {
+            message_rate: 0.001,
+            message_burst: 1,
+            max_rate_violations: Some(2),
+            ..Config::default()
+        };
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets.insert(0, Member::new(sender, &config));
+
+        // two bursts of one message and two violations each, far enough apart
+        // for the bucket to refill in between
+        for burst in 1..=2 {
+            for _ in 0..3 {
+                sender_client
+                    .write_all(&client_text_frame("hi"))
+                    .await
+                    .unwrap();
+            }
+            relay_until(&mut room, &app_data, |room| {
+                room.sockets.is_empty() || received(room, 0) == 3 * burst
+            })
+            .await;
+            assert!(room.sockets.contains_key(&0));
+            tokio::time::advance(Duration::from_secs(1000)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_is_removed() {
+        let config = Config {
+            max_full_send_cycles: Some(3),
+            ..Config::default()
+        };
+        let app_data = AppData::default();
+        // the peer never reads, so the send queue fills up and stays full
+        let (server, _client) = tokio::io::duplex(16);
+        let socket = WebSocket::new(server);
+        let message = Arc::new(Message::Text("a".repeat(64)));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !socket.is_send_queue_full() {
+                let _ = socket.try_send_now(Arc::clone(&message));
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut room = RoomData::default();
+        room.sockets.insert(0, Member::new(socket, &config));
+
+        for _ in 0..3 {
+            relay_room_messages("room", &mut room, &app_data).await;
+            assert!(room.sockets.contains_key(&0));
+        }
+        relay_room_messages("room", &mut room, &app_data).await;
+        assert!(room.sockets.is_empty());
+    }
+
+    /// Returns a member with the given send policy whose peer never reads,
+    /// so its send queue stays full once filled.
+    fn stalled_member(send_policy: SendPolicy) -> (Member, tokio::io::DuplexStream) {
+        let config = Config {
+            send_policy,
+            ..Config::default()
+        };
+        let (server, client) = tokio::io::duplex(16);
+        (Member::new(WebSocket::new(server), &config), client)
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_newest() {
+        let (mut member, _client) = stalled_member(SendPolicy::DropOldest);
+        // more than fit into the send queue and the outbox together
+        let count = MAX_OUTBOX_LEN + 20;
+        let messages = (0..count)
+            .map(|i| Arc::new(Message::Text(i.to_string())))
+            .collect::<Vec<_>>();
+        let mut queued = 0;
+        for message in &messages {
+            queued += member.relay(Arc::clone(message)).await.unwrap().messages;
+        }
+        assert!(member.socket.is_send_queue_full());
+        assert_eq!(member.outbox.len(), MAX_OUTBOX_LEN);
+        // neither the held back nor the dropped messages count as queued
+        assert!(queued as usize + MAX_OUTBOX_LEN < count);
+        assert!(Arc::ptr_eq(
+            member.outbox.back().unwrap(),
+            messages.last().unwrap()
+        ));
+        // the oldest messages that didn't fit into the send queue are gone
+        let oldest = &messages[count - MAX_OUTBOX_LEN - 1];
+        assert!(!member.outbox.iter().any(|m| Arc::ptr_eq(m, oldest)));
+    }
+
+    #[tokio::test]
+    async fn test_held_back_messages_count_once_flushed() {
+        let (mut member, mut client) = stalled_member(SendPolicy::DropOldest);
+        let message = Arc::new(Message::Text(String::from("a")));
+        while member.outbox.len() < MAX_OUTBOX_LEN {
+            member.relay(Arc::clone(&message)).await.unwrap();
+        }
+        assert_eq!(
+            member.relay(Arc::clone(&message)).await.unwrap(),
+            Queued::default()
+        );
+
+        // once the client reads, the held back messages reach the send queue
+        task::spawn(async move { while client.read(&mut [0; 64]).await.unwrap() > 0 {} });
+        let mut flushed = Queued::default();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !member.outbox.is_empty() {
+                let queued = member.flush_outbox().unwrap();
+                flushed.messages += queued.messages;
+                flushed.bytes += queued.bytes;
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        let expected = MAX_OUTBOX_LEN as u64;
+        assert_eq!(
+            flushed,
+            Queued {
+                messages: expected,
+                bytes: expected
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_policy_removes_backed_up_member() {
+        let (mut member, _client) = stalled_member(SendPolicy::Disconnect);
+        let message = Arc::new(Message::Text(String::from("a")));
+        while !member.socket.is_send_queue_full() {
+            let queued = member.relay(Arc::clone(&message)).await.unwrap();
+            assert_eq!(queued, Queued::one(&message));
+        }
+        assert!(matches!(
+            member.relay(message).await,
+            Err(RemovalReason::SlowConsumer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_is_answered_not_relayed() {
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let (receiver, mut receiver_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(sender, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(receiver, &app_data.config));
+
+        sender_client
+            .write_all(&client_text_frame(r#"{"type":"hb"}"#))
+            .await
+            .unwrap();
+        relay_until(&mut room, &app_data, |room| received(room, 0) == 1).await;
+
+        let ack = br#"{"type":"hb-ack"}"#;
+        let mut buf = vec![0; 2 + ack.len()];
+        tokio::time::timeout(Duration::from_secs(1), sender_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[..2], [0x81, ack.len() as u8]);
+        assert_eq!(&buf[2..], ack);
+        let relayed =
+            tokio::time::timeout(Duration::from_millis(50), receiver_client.read(&mut buf));
+        assert!(relayed.await.is_err(), "heartbeat was relayed");
+        assert!(room.history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_of_backed_up_member_does_not_block_room() {
+        let app_data = AppData::new(Config {
+            max_full_send_cycles: None,
+            ..Config::default()
+        });
+        let (sender, mut sender_client) = socket_pair().await;
+        let (receiver, mut receiver_client) = socket_pair().await;
+        for _ in 0..3 {
+            sender_client
+                .write_all(&client_text_frame(r#"{"type":"hb"}"#))
+                .await
+                .unwrap();
+        }
+        sender_client
+            .write_all(&client_text_frame("dit"))
+            .await
+            .unwrap();
+        while sender.stats().messages_received < 4 {
+            task::yield_now().await;
+        }
+        // the sender doesn't read, so its send queue fills up
+        let message = Arc::new(Message::Binary(vec![0; 1 << 20].into()));
+        while sender.try_send_now(Arc::clone(&message)).is_ok() {
+            task::yield_now().await;
+        }
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(sender, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(receiver, &app_data.config));
+
+        // each pass takes one message of the sender
+        let relay = async {
+            for _ in 0..4 {
+                relay_room_messages("room", &mut room, &app_data).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), relay)
+            .await
+            .expect("heartbeat ack blocked the relay");
+
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, [0x81, 3, b'd', b'i', b't']);
+    }
+
+    #[tokio::test]
+    async fn test_ping_members() {
+        let app_data = AppData::default();
+        let mut room = RoomData::default();
+        let mut clients = Vec::new();
+        for id in 0..2 {
+            let (socket, client) = socket_pair().await;
+            room.sockets
+                .insert(id, Member::new(socket, &app_data.config));
+            clients.push(client);
+        }
+
+        room.ping_members();
+        for client in &mut clients {
+            let mut buf = [0; 2];
+            tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf, [0x89, 0x00]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_not_relayed() {
+        let app_data = AppData::new(Config {
+            max_chat_len: Some(4),
+            ..Config::default()
+        });
+        let (sender, mut sender_client) = socket_pair().await;
+        let (receiver, mut receiver_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(sender, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(receiver, &app_data.config));
+
+        // four characters in more than four bytes pass, five don't
+        sender_client
+            .write_all(&client_text_frame("äöüß"))
+            .await
+            .unwrap();
+        sender_client
+            .write_all(&client_text_frame("dahdit"))
+            .await
+            .unwrap();
+        relay_until(&mut room, &app_data, |room| received(room, 0) == 2).await;
+
+        let relayed = "äöüß".as_bytes();
+        let mut buf = vec![0; 2 + relayed.len()];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[2..], relayed);
+        let more = tokio::time::timeout(Duration::from_millis(50), receiver_client.read(&mut buf));
+        assert!(more.await.is_err(), "oversized message was relayed");
+
+        assert_eq!(
+            read_control(&mut sender_client).await,
+            Some(ControlMessage::Error {
+                message: String::from("message longer than 4 characters")
+            })
+        );
+        assert_eq!(room.history.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pong_not_relayed() {
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let (receiver, mut receiver_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(sender, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(receiver, &app_data.config));
+
+        // an empty, masked Pong as browsers send it in reply to a ping
+        sender_client
+            .write_all(&[0x8a, 0x80, 1, 2, 3, 4])
+            .await
+            .unwrap();
+        sender_client
+            .write_all(&client_text_frame("dit"))
+            .await
+            .unwrap();
+        relay_until(&mut room, &app_data, |room| received(room, 0) == 1).await;
+
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, [0x81, 3, b'd', b'i', b't']);
+        assert_eq!(room.history.len(), 1);
+        assert!(matches!(&*room.history[0], Message::Text(text) if text == "dit"));
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_reaches_only_target() {
+        let app_data = AppData::default();
+        let mut room = RoomData::default();
+        let mut clients = Vec::new();
+        for id in 0..3 {
+            let (socket, client) = socket_pair().await;
+            room.sockets
+                .insert(id, Member::new(socket, &app_data.config));
+            clients.push(client);
+        }
+
+        for text in [
+            r#"{"type":"dm","to":2,"text":"hi"}"#,
+            r#"{"type":"dm","to":9,"text":"hi"}"#,
+        ] {
+            clients[0]
+                .write_all(&client_text_frame(text))
+                .await
+                .unwrap();
+        }
+        relay_until(&mut room, &app_data, |room| received(room, 0) == 2).await;
+
+        let direct = br#"{"type":"dm","from":0,"text":"hi"}"#;
+        let mut buf = vec![0; 2 + direct.len()];
+        tokio::time::timeout(Duration::from_secs(1), clients[2].read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[2..], direct);
+        let error = br#"{"type":"error","message":"couldn't deliver message to member 9"}"#;
+        let mut buf = vec![0; 2 + error.len()];
+        tokio::time::timeout(Duration::from_secs(1), clients[0].read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[2..], error);
+        let relayed = tokio::time::timeout(Duration::from_millis(50), clients[1].read(&mut buf));
+        assert!(relayed.await.is_err(), "direct message was relayed");
+        assert!(room.history.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abandoned_room_is_reaped() {
+        let app_data = app_data_with_rooms(&["kept", "abandoned"]);
+        remove_empty_rooms(&app_data).await;
+        assert_eq!(app_data.rooms.lock().await.len(), 2);
+
+        tokio::time::advance(app_data.config.room_ttl).await;
+        app_data.rooms.lock().await.insert(
+            "kept".to_owned(),
+            RoomData::default().spawn_shared("kept", &app_data),
+        );
+        remove_empty_rooms(&app_data).await;
+        let rooms = app_data.rooms.lock().await;
+        assert!(rooms.contains_key("kept"));
+        assert!(!rooms.contains_key("abandoned"));
+    }
+
+    #[tokio::test]
+    async fn test_default_room() {
+        let app_data = Arc::new(AppData::default());
+        add_default_room(&app_data).await;
+        let rooms = app_data.rooms.lock().await;
+        assert_eq!(
+            rooms.keys().collect::<Vec<_>>(),
+            [config::DEFAULT_ROOM_NAME]
+        );
+        let room = rooms[config::DEFAULT_ROOM_NAME].lock().await;
+        assert!(room.options.permanent);
+    }
+
+    #[tokio::test]
+    async fn test_default_room_disabled() {
+        let app_data = Arc::new(AppData::new(Config {
+            default_room: None,
+            ..Config::default()
+        }));
+        add_default_room(&app_data).await;
+        let request = Request::try_parse_from(&b"GET /api/rooms HTTP/1.1\r\n\r\n"[..])
+            .await
+            .unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"total":0,"rooms":[]}"#));
+    }
+
+    #[tokio::test]
+    async fn test_join_default_room_without_room_param() {
+        let app_data = Arc::new(AppData::default());
+        add_default_room(&app_data).await;
+        let mut client = connect(&app_data, "/ws?nick=bob").await;
+        read_welcome(&mut client).await;
+        let room = app_data.lock_room(config::DEFAULT_ROOM_NAME).await.unwrap();
+        assert_eq!(room.sockets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_join_without_room_param_or_default_room() {
+        let config = Config {
+            default_room: None,
+            ..Config::default()
+        };
+        let request = ws_request_to("/ws", TEST_KEY, "Sec-WebSocket-Version: 13\r\n").await;
+        let response = response_string(try_upgrade_to_ws(&request, &config).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.room, config::DEFAULT_ROOM_NAME);
+    }
+
+    /// Lets clients join if they send the `x-api-key` header, as the user
+    /// named by the key.
+    struct ApiKeyAuthorizer;
+
+    impl JoinAuthorizer for ApiKeyAuthorizer {
+        fn authorize<'a>(
+            &'a self,
+            request: &'a Request,
+            _room: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<AuthInfo, AuthError>> + Send + 'a>> {
+            Box::pin(async move {
+                let key = request.header("x-api-key");
+                match key {
+                    Some(key) => Ok(AuthInfo {
+                        user_id: Some(key.to_owned()),
+                    }),
+                    None => Err(AuthError::new(Status::Unauthorized, "missing api key")),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_join_authorizer() {
+        let app_data = Arc::new(AppData::with_authorizer(
+            Config::default(),
+            Box::new(ApiKeyAuthorizer),
+        ));
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        for (header, expected) in [
+            ("", "HTTP/1.1 401 Unauthorized\r\n"),
+            (
+                "X-Api-Key: alice\r\n",
+                "HTTP/1.1 101 Switching Protocols\r\n",
+            ),
+        ] {
+            let headers = format!("Sec-WebSocket-Version: 13\r\n{}", header);
+            let request = ws_request(&headers).await;
+            let (server, mut client) = tokio::io::duplex(1024);
+            let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+            let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+            handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit).await;
+            let mut head = vec![0; expected.len()];
+            client.read_exact(&mut head).await.unwrap();
+            assert_eq!(String::from_utf8(head).unwrap(), expected);
+        }
+
+        let room = app_data.lock_room("a").await.unwrap();
+        let members = room.sockets.values().collect::<Vec<_>>();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].auth.user_id.as_deref(), Some("alice"));
+        assert_eq!(
+            app_data
+                .metrics
+                .connections_rejected
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_right_after_handshake() {
+        let app_data = Arc::new(AppData::default());
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        // too small for the welcome message, so it can't be written before the
+        // client is gone
+        let (server, mut client) = tokio::io::duplex(64);
+        let client = task::spawn(async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+            // dropping the client resets the connection
+        });
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+        handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit).await;
+        client.await.unwrap();
+
+        assert!(app_data.lock_room("a").await.unwrap().sockets.is_empty());
+        // the token was released, so it expires after the grace period
+        let tokens = app_data.reconnect_tokens.lock().unwrap();
+        assert!(tokens.values().all(|token| token.left_at.is_some()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_client_does_not_block_room() {
+        let app_data = Arc::new(AppData::default());
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        // the client stops reading after the handshake response, so the
+        // welcome message doesn't fit
+        let (server, mut client) = tokio::io::duplex(64);
+        let read_head = async {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.read_u8().await.unwrap());
+            }
+            // the room can be locked while the welcome is stuck
+            let start = Instant::now();
+            drop(app_data.lock_room("a").await.unwrap());
+            assert!(start.elapsed() < Duration::from_secs(1));
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+        let join = handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit);
+        tokio::join!(join, read_head);
+
+        let room = app_data.rooms.lock().await["a"].clone();
+        assert!(room.try_lock().unwrap().sockets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_frame_sent_with_handshake_is_kept() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let mut alice = TestClient::connect(&app_data, "/ws?room=a").await;
+
+        let (server, client) = tokio::io::duplex(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+        task::spawn(handle_connection(
+            server,
+            addr,
+            Arc::clone(&app_data),
+            permit,
+        ));
+        let mut client = BufReader::new(client);
+        // the frame arrives in the same read as the request
+        let mut request =
+            upgrade_request("/ws?room=a", TEST_KEY, "Sec-WebSocket-Version: 13\r\n").into_bytes();
+        request.extend_from_slice(&client_text_frame("eager"));
+        client.write_all(&request).await.unwrap();
+
+        let frame = alice.next_frame().await;
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), b"eager");
+    }
+
+    #[tokio::test]
+    async fn test_join_unknown_room() {
+        let app_data = Arc::new(AppData::default());
+        add_default_room(&app_data).await;
+        let request = ws_request_to(
+            "/ws?room=nowhere",
+            TEST_KEY,
+            "Sec-WebSocket-Version: 13\r\n",
+        )
+        .await;
+        let (server, mut client) = tokio::io::duplex(1024);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let permit = app_data.try_acquire_connection(addr.ip()).unwrap();
+        handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit).await;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("no room with name nowhere found"));
+    }
+
+    #[tokio::test]
+    async fn test_room_options() {
+        let room = RoomData::with_options(RoomOptions::from_config(&Config {
+            history_size: 7,
+            ..Config::default()
+        }));
+        assert_eq!(room.options.history_size, 7);
+        assert!(!room.options.permanent);
+        assert_eq!(room.options.max_members, None);
+        assert_eq!(room.options.password, None);
+        assert_eq!(room.admits(None), Ok(()));
+
+        let options = RoomOptions {
+            max_members: Some(1),
+            permanent: true,
+            history_size: 0,
+            password: Some(String::from("secret")),
+        };
+        let mut room = RoomData::with_options(options.clone());
+        assert_eq!(room.options, options);
+        assert!(room.admits(None).is_err());
+        assert!(room.admits(Some("wrong")).is_err());
+        assert_eq!(room.admits(Some("secret")), Ok(()));
+        let (socket, _client) = socket_pair().await;
+        room.sockets
+            .insert(0, Member::new(socket, &Config::default()));
+        assert_eq!(room.admits(Some("secret")), Err("room is full."));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_permanent_room_survives_cleanup() {
+        let app_data = app_data_with_rooms(&[]);
+        let mut room = RoomData::with_options(RoomOptions {
+            permanent: true,
+            ..RoomOptions::default()
+        });
+        room.is_deletable = true;
+        app_data.rooms.lock().await.insert(
+            config::DEFAULT_ROOM_NAME.to_owned(),
+            room.spawn_shared(config::DEFAULT_ROOM_NAME, &app_data),
+        );
+
+        tokio::time::advance(app_data.config.room_ttl * 2).await;
+        remove_empty_rooms(&app_data).await;
+        assert!(app_data
+            .rooms
+            .lock()
+            .await
+            .contains_key(config::DEFAULT_ROOM_NAME));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let app_data = app_data_with_rooms(&["a", "b"]);
+        let (socket, _client) = socket_pair().await;
+        app_data
+            .lock_room("a")
+            .await
+            .unwrap()
+            .sockets
+            .insert(0, Member::new(socket, &app_data.config));
+
+        let response = handle_health(&app_data).await.into_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"status\":\"ok\",\"rooms\":2,\"connections\":1}"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_relayed_messages() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let (mut sender_client, mut receiver_client);
+        {
+            let mut room = app_data.lock_room("a").await.unwrap();
+            let (sender, receiver);
+            (sender, sender_client) = room_socket_pair(&room).await;
+            (receiver, receiver_client) = room_socket_pair(&room).await;
+            room.sockets
+                .insert(0, Member::new(sender, &app_data.config));
+            room.sockets
+                .insert(1, Member::new(receiver, &app_data.config));
+        }
+
+        for _ in 0..3 {
+            sender_client
+                .write_all(&client_text_frame("hi"))
+                .await
+                .unwrap();
+        }
+        let mut buf = [0; 12];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let response = handle_metrics(&app_data).await.into_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nmorse_chat_rooms 1\n"));
+        assert!(response.contains("\nmorse_chat_connections 2\n"));
+        assert!(response.contains("\nmorse_chat_messages_relayed_total 3\n"));
+        assert!(response.contains("# TYPE morse_chat_messages_relayed_total counter\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_relayed_bytes() {
+        let app_data = app_data_with_rooms(&["a", "b"]);
+        let (mut sender_client, mut first_client, mut second_client);
+        {
+            let mut room = app_data.lock_room("a").await.unwrap();
+            let (sender, first, second);
+            (sender, sender_client) = room_socket_pair(&room).await;
+            (first, first_client) = room_socket_pair(&room).await;
+            (second, second_client) = room_socket_pair(&room).await;
+            for (id, socket) in [(0, sender), (1, first), (2, second)] {
+                room.sockets
+                    .insert(id, Member::new(socket, &app_data.config));
+            }
+        }
+
+        sender_client
+            .write_all(&client_text_frame("dah:ABCDE"))
+            .await
+            .unwrap();
+        for client in [&mut first_client, &mut second_client] {
+            let mut buf = [0; 11];
+            tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let response = handle_metrics(&app_data).await.into_bytes();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("\nmorse_chat_bytes_relayed_total 18\n"));
+        assert!(response.contains("\nmorse_chat_room_bytes_relayed_total{room=\"a\"} 18\n"));
+        assert!(response.contains("\nmorse_chat_room_bytes_relayed_total{room=\"b\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_late_joiner_receives_history() {
+        let app_data = Arc::new(AppData::new(Config {
+            history_size: 2,
+            ..Config::default()
+        }));
+        let room = RoomData::with_options(RoomOptions::from_config(&app_data.config))
+            .spawn_shared("a", &app_data);
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+        let (mut sender_client, mut receiver_client);
+        {
+            let mut room = app_data.lock_room("a").await.unwrap();
+            let (sender, receiver);
+            (sender, sender_client) = room_socket_pair(&room).await;
+            (receiver, receiver_client) = room_socket_pair(&room).await;
+            room.sockets
+                .insert(0, Member::new(sender, &app_data.config));
+            room.sockets
+                .insert(1, Member::new(receiver, &app_data.config));
+        }
+        for text in ["1", "2", "3"] {
+            sender_client
+                .write_all(&client_text_frame(text))
+                .await
+                .unwrap();
+        }
+        let mut buf = [0; 9];
+        tokio::time::timeout(Duration::from_secs(1), receiver_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (server, client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let addr = server.peer_addr().unwrap();
+        handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit).await;
+
+        let mut client = BufReader::new(client);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            client.read_line(&mut head).await.unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        read_welcome(&mut client).await;
+        assert_eq!(
+            read_control(&mut client).await,
+            Some(ControlMessage::History {
+                messages: vec![String::from("2"), String::from("3")]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_replay_does_not_wait_on_joiner() {
+        let app_data = Arc::new(AppData::default());
+        let mut room = RoomData::default();
+        for _ in 0..app_data.config.history_size {
+            room.history
+                .push_back(Arc::new(Message::Text("a".repeat(64 * 1024))));
+        }
+        let room = Arc::new(Mutex::new(room));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        // the client reads the handshake and welcome but nothing after that
+        let mut client = connect(&app_data, "/ws?room=a").await;
+        read_welcome(&mut client).await;
+        let room = tokio::time::timeout(Duration::from_secs(1), app_data.lock_room("a"));
+        let room = room.await.expect("history replay kept the room locked");
+        assert_eq!(room.unwrap().sockets.len(), 1);
+    }
+
+    /// Drops the client end and relays until its member left room `a`.
+    async fn leave(app_data: &AppData, client: BufReader<tokio::io::DuplexStream>) {
+        drop(client);
+        let mut room = app_data.lock_room("a").await.unwrap();
+        while !room.sockets.is_empty() {
+            relay_room_messages("a", &mut room, app_data).await;
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_within_grace_period() {
+        let app_data = Arc::new(AppData::default());
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        let mut client = connect(&app_data, "/ws?room=a&nick=bob").await;
+        let (id, token) = read_welcome(&mut client).await;
+        leave(&app_data, client).await;
+        tokio::time::advance(app_data.config.reconnect_grace / 2).await;
+        app_data.remove_expired_reconnect_tokens();
+
+        let path = format!("/ws?room=a&token={}", token);
+        let mut client = connect(&app_data, &path).await;
+        assert_eq!(read_welcome(&mut client).await, (id, token));
+        let room = app_data.lock_room("a").await.unwrap();
+        assert_eq!(room.sockets[&id].nick.as_deref(), Some("bob"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_keeps_nick_unique() {
+        let app_data = Arc::new(AppData::default());
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        let mut client = connect(&app_data, "/ws?room=a&nick=bob").await;
+        let (id, token) = read_welcome(&mut client).await;
+        let path = format!("/ws?room=a&token={}", token);
+        // replacing the still connected member keeps its nick
+        let mut client = connect(&app_data, &path).await;
+        assert_eq!(read_welcome(&mut client).await, (id, token.clone()));
+        let room = app_data.lock_room("a").await.unwrap();
+        assert_eq!(room.sockets[&id].nick.as_deref(), Some("bob"));
+        drop(room);
+
+        // someone else took the nick while the member was gone
+        leave(&app_data, client).await;
+        let mut other = connect(&app_data, "/ws?room=a&nick=bob").await;
+        let (other_id, _) = read_welcome(&mut other).await;
+        let mut client = connect(&app_data, &path).await;
+        assert_eq!(read_welcome(&mut client).await, (id, token.clone()));
+        let room = app_data.lock_room("a").await.unwrap();
+        assert_eq!(room.sockets[&other_id].nick.as_deref(), Some("bob"));
+        assert_eq!(room.sockets[&id].nick.as_deref(), Some("bob-2"));
+        let tokens = app_data.reconnect_tokens.lock().unwrap();
+        assert_eq!(tokens[&token].nick.as_deref(), Some("bob-2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_with_expired_token() {
+        let app_data = Arc::new(AppData::default());
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        let mut client = connect(&app_data, "/ws?room=a&nick=bob").await;
+        let (id, token) = read_welcome(&mut client).await;
+        leave(&app_data, client).await;
+        tokio::time::advance(app_data.config.reconnect_grace + Duration::from_secs(1)).await;
+        app_data.remove_expired_reconnect_tokens();
+        assert!(!app_data
+            .reconnect_tokens
+            .lock()
+            .unwrap()
+            .contains_key(&token));
+
+        let path = format!("/ws?room=a&token={}", token);
+        let mut client = connect(&app_data, &path).await;
+        let (new_id, new_token) = read_welcome(&mut client).await;
+        assert_ne!(new_id, id);
+        assert_ne!(new_token, token);
+        let room = app_data.lock_room("a").await.unwrap();
+        assert_eq!(room.sockets[&new_id].nick, None);
+    }
+
+    /// Collects formatted log output for tests.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_task_logs_carry_connection() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let app_data = Arc::new(AppData::default());
+        let room = RoomData::default().spawn_shared("a", &app_data);
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        let (server, client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let addr = server.peer_addr().unwrap();
+        handle_new_ws(&request, server, addr, Arc::clone(&app_data), permit).await;
+
+        let mut client = BufReader::new(client);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            client.read_line(&mut head).await.unwrap();
+        }
+        read_welcome(&mut client).await;
+        // close with the invalid status code 999, which the stream task logs
+        client
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe7])
+            .await
+            .unwrap();
+        let mut buf = [0; 4];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("invalid close code."))
+            .unwrap();
+        assert!(line.contains("room_name=\"a\""), "{}", line);
+        assert!(line.contains("id=0"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn test_connection_ids_are_unique() {
+        let app_data = Arc::new(AppData::default());
+        let tasks = (0..8)
+            .map(|_| {
+                let app_data = Arc::clone(&app_data);
+                task::spawn(async move {
+                    (0..1000)
+                        .map(|_| app_data.next_connection_id())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = std::collections::HashSet::new();
+        for task in tasks {
+            for id in task.await.unwrap() {
+                assert!(ids.insert(id), "duplicate connection id {}", id);
+            }
+        }
+        assert_eq!(ids.len(), 8000);
+    }
+
+    /// Reads one response with a `content-length` and returns its head and body.
+    async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> (String, String) {
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut head).await.unwrap(), 0);
+        }
+        let length = head
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_serves_sequential_requests() {
+        let app_data = Arc::new(AppData::default());
+        let (server, client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let addr = server.peer_addr().unwrap();
+        let connection = task::spawn(handle_connection(server, addr, app_data, permit));
+        let mut client = BufReader::new(client);
+
+        client
+            .write_all(b"GET /api/health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nconnection: keep-alive\r\n"));
+        assert!(body.contains("\"status\""));
+
+        client
+            .write_all(b"GET /api/health HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+
+        tokio::time::timeout(Duration::from_secs(1), connection)
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_1_0_closes_by_default() {
+        let app_data = Arc::new(AppData::default());
+        let (server, client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let addr = server.peer_addr().unwrap();
+        let connection = task::spawn(handle_connection(server, addr, app_data, permit));
+        let mut client = BufReader::new(client);
+
+        client
+            .write_all(b"GET /api/health HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nconnection: keep-alive\r\n"));
+
+        client
+            .write_all(b"GET /api/health HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+
+        tokio::time::timeout(Duration::from_secs(1), connection)
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_http_version() {
+        let app_data = Arc::new(AppData::default());
+        let (server, client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let addr = server.peer_addr().unwrap();
+        let connection = task::spawn(handle_connection(server, addr, app_data, permit));
+        let mut client = BufReader::new(client);
+
+        client
+            .write_all(b"GET /api/health HTTP/2.0\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        assert!(head.contains("\r\nconnection: close\r\n"));
+        tokio::time::timeout(Duration::from_secs(1), connection)
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_request_times_out() {
+        let app_data = Arc::new(AppData::new(Config {
+            request_timeout: Duration::from_millis(50),
+            ..Config::default()
+        }));
+        let (server, mut client) = tcp_pair().await;
+        let permit = app_data
+            .try_acquire_connection(client.local_addr().unwrap().ip())
+            .unwrap();
+        let addr = server.peer_addr().unwrap();
+        let connection = task::spawn(handle_connection(server, addr, app_data, permit));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: loc")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+        connection.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_does_not_block_other_connections() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let server = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(serve(server, Arc::clone(&app_data), None));
+
+        // joining room a stalls until the room is unlocked again
+        let _room_a = app_data.lock_room("a").await.unwrap();
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        let request = upgrade_request("/ws?room=a", TEST_KEY, "Sec-WebSocket-Version: 13\r\n");
+        slow.write_all(request.as_bytes()).await.unwrap();
+
+        let mut fast = TcpStream::connect(addr).await.unwrap();
+        fast.write_all(b"GET /index.html HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 15];
+        tokio::time::timeout(Duration::from_secs(1), fast.read_exact(&mut buf))
+            .await
+            .expect("second connection was blocked by the first")
+            .unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_connections_above_cap_rejected() {
+        let app_data = Arc::new(AppData::new(Config {
+            max_connections: 1,
+            ..Config::default()
+        }));
+        let server = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(serve(server, Arc::clone(&app_data), None));
+
+        // holds the only permit while the server waits for its request
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), second.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connections_above_ip_limit_rejected() {
+        let app_data = Arc::new(AppData::new(Config {
+            max_connections_per_ip: 2,
+            ..Config::default()
+        }));
+        let server = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(serve(server, Arc::clone(&app_data), None));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), third.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_client_ip() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let raw = "GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7, 10.0.0.1\r\n\r\n";
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        assert_eq!(
+            client_ip(&request, peer, true),
+            IpAddr::from([203, 0, 113, 7])
+        );
+        assert_eq!(client_ip(&request, peer, false), peer);
+
+        let raw = "GET / HTTP/1.1\r\nX-Forwarded-For: unknown\r\n\r\n";
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        assert_eq!(client_ip(&request, peer, true), peer);
+    }
+
+    #[tokio::test]
+    async fn test_wss_handshake() {
+        use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let acceptor = tls::acceptor_from_pem(
+            cert.serialize_pem().unwrap().as_bytes(),
+            cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+        let app_data = app_data_with_rooms(&["a"]);
+        let server = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(serve(server, Arc::clone(&app_data), Some(acceptor)));
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = BufReader::new(connector.connect(server_name, stream).await.unwrap());
+
+        let request = upgrade_request("/ws?room=a", TEST_KEY, "Sec-WebSocket-Version: 13\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(client.read_line(&mut head).await.unwrap(), 0);
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        read_welcome(&mut client).await;
+
+        // the websocket runs over the tls stream, so the heartbeat is answered
+        client
+            .write_all(&client_text_frame(r#"{"type":"hb"}"#))
+            .await
+            .unwrap();
+        client.flush().await.unwrap();
+        let ack = br#"{"type":"hb-ack"}"#;
+        let mut buf = vec![0; 2 + ack.len()];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[2..], ack);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_ip_limited_behind_trusted_proxy() {
+        let app_data = Arc::new(AppData::new(Config {
+            max_connections_per_ip: 1,
+            trust_proxy: true,
+            ..Config::default()
+        }));
+        let server = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(serve(server, Arc::clone(&app_data), None));
+
+        let request = |ip: &str| {
+            format!(
+                "GET /api/health HTTP/1.1\r\nX-Forwarded-For: {}\r\n\r\n",
+                ip
+            )
+        };
+        // both connections come from the proxy, but from different clients
+        let mut first = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        first
+            .write_all(request("203.0.113.7").as_bytes())
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut first).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let mut second = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        second
+            .write_all(request("203.0.113.8").as_bytes())
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut second).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(
+            app_data
+                .ip_connections
+                .count(IpAddr::from([203, 0, 113, 7])),
+            1
+        );
+        assert_eq!(app_data.ip_connections.count(addr.ip()), 0);
+
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        third
+            .write_all(request("203.0.113.7").as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), third.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+    }
+
+    /// Parses a websocket upgrade request for room `a` with additional headers.
+    async fn ws_request(extra_headers: &str) -> Request {
+        ws_request_with_key(TEST_KEY, extra_headers).await
+    }
+
+    async fn ws_request_with_key(key: &str, extra_headers: &str) -> Request {
+        ws_request_to("/ws?room=a", key, extra_headers).await
+    }
+
+    async fn ws_request_to(path: &str, key: &str, extra_headers: &str) -> Request {
+        let raw = upgrade_request(path, key, extra_headers);
+        Request::try_parse_from(raw.as_bytes()).await.unwrap()
+    }
+
+    fn response_string(response: Response) -> String {
+        String::from_utf8(response.into_bytes()).unwrap()
+    }
+
+    async fn gen_room_request(body: &str) -> Request {
+        let raw = format!(
+            "POST /api/gen-room HTTP/1.1\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {}",
+            body.len(),
+            body
+        );
+        Request::try_parse_from(raw.as_bytes()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_create_named_room() {
+        let app_data = app_data_with_rooms(&[]);
+        let request = gen_room_request(r#"{"name":"lobby-1"}"#).await;
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"status":0,"name":"lobby-1"}"#));
+        assert!(app_data.rooms.lock().await.contains_key("lobby-1"));
+
+        let request = gen_room_request(r#"{"name":"../lobby"}"#).await;
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_create_room_with_password() {
+        let app_data = app_data_with_rooms(&[]);
+        let request = gen_room_request(r#"{"name":"team","password":"hunter2"}"#).await;
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.ends_with(r#"{"status":0,"name":"team"}"#));
+        let room = app_data.lock_room("team").await.unwrap();
+        assert_eq!(room.options.password.as_deref(), Some("hunter2"));
+        assert!(room.admits(Some("hunter")).is_err());
+        assert_eq!(room.admits(Some("hunter2")), Ok(()));
+        drop(room);
+
+        // an empty password doesn't protect the room
+        let request = gen_room_request(r#"{"name":"open","password":""}"#).await;
+        handle(&request, &app_data).await;
+        let room = app_data.lock_room("open").await.unwrap();
+        assert_eq!(room.options.password, None);
+    }
+
+    async fn delete_room(app_data: &SharedAppData, name: &str) -> String {
+        let raw = format!("DELETE /api/rooms/{} HTTP/1.1\r\n\r\n", name);
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        response_string(handle(&request, app_data).await)
+    }
+
+    #[tokio::test]
+    async fn test_delete_room() {
+        let app_data = app_data_with_rooms(&["a", "b"]);
+        let response = delete_room(&app_data, "a").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!response.contains("content-length"));
+        let rooms = app_data.rooms.lock().await;
+        assert!(!rooms.contains_key("a"));
+        assert!(rooms.contains_key("b"));
+    }
+
+    async fn close_room(app_data: &SharedAppData, name: &str, token: Option<&str>) -> String {
+        let authorization = token.map_or(String::new(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        let raw = format!(
+            "POST /api/admin/rooms/{}/close HTTP/1.1\r\n{}\r\n",
+            name, authorization
+        );
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        response_string(handle(&request, app_data).await)
+    }
+
+    #[tokio::test]
+    async fn test_admin_closes_room() {
+        let app_data = Arc::new(AppData::new(Config {
+            admin_token: Some(String::from("secret")),
+            ..Config::default()
+        }));
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+        let mut first = connect(&app_data, "/ws?room=a").await;
+        let mut second = connect(&app_data, "/ws?room=a").await;
+        read_welcome(&mut first).await;
+        read_welcome(&mut second).await;
+
+        let response = close_room(&app_data, "a", None).await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = close_room(&app_data, "a", Some("guess")).await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert_eq!(app_data.lock_room("a").await.unwrap().sockets.len(), 2);
+
+        let response = close_room(&app_data, "a", Some("secret")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"disconnected":2}"#));
+        assert!(!app_data.rooms.lock().await.contains_key("a"));
+        for client in [&mut first, &mut second] {
+            let close = Frame::try_parse_from(client).await.unwrap();
+            assert_eq!(close.opcode(), OpCode::Close);
+            assert_eq!(&close.payload()[..2], &CLOSE_GOING_AWAY.to_be_bytes());
+            assert_eq!(&close.payload()[2..], b"room closed by admin");
+        }
+
+        let response = close_room(&app_data, "a", Some("secret")).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_closing_room_does_not_wait_on_slow_member() {
+        let app_data = Arc::new(AppData::new(Config {
+            admin_token: Some(String::from("secret")),
+            ..Config::default()
+        }));
+        let (socket, _client) = socket_pair().await;
+        // the client doesn't read, so the send queue fills up
+        let message = Arc::new(Message::Binary(vec![0; 1 << 20].into()));
+        while socket.try_send_now(Arc::clone(&message)).is_ok() {
+            task::yield_now().await;
+        }
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(socket, &app_data.config));
+        app_data
+            .rooms
+            .lock()
+            .await
+            .insert(String::from("a"), Arc::new(Mutex::new(room)));
+
+        let response = close_room(&app_data, "a", Some("secret"));
+        let response = tokio::time::timeout(Duration::from_secs(1), response)
+            .await
+            .expect("closing the room waited on the slow member");
+        assert!(response.ends_with(r#"{"disconnected":1}"#));
+        assert!(!app_data.rooms.lock().await.contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_announcement_reaches_all_rooms() {
+        let app_data = Arc::new(AppData::new(Config {
+            admin_token: Some(String::from("secret")),
+            ..Config::default()
+        }));
+        let mut clients = Vec::new();
+        for name in ["a", "b"] {
+            let mut room = RoomData::default();
+            for id in 0..2 {
+                let (socket, client) = socket_pair().await;
+                room.sockets
+                    .insert(id, Member::new(socket, &app_data.config));
+                clients.push(client);
+            }
+            let room = Arc::new(Mutex::new(room));
+            app_data.rooms.lock().await.insert(name.to_owned(), room);
+        }
+
+        let body = r#"{"message":"restarting in 5 minutes"}"#;
+        let raw = format!(
+            "POST /api/admin/announce HTTP/1.1\r\n\
+             Authorization: Bearer secret\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {}",
+            body.len(),
+            body
+        );
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"recipients":4}"#));
+        for client in &mut clients {
+            assert_eq!(
+                read_control(client).await,
+                Some(ControlMessage::System {
+                    message: String::from("restarting in 5 minutes")
+                })
+            );
+        }
+
+        let raw = "POST /api/admin/announce HTTP/1.1\r\n\r\n";
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_api_disabled_without_token() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let response = close_room(&app_data, "a", Some("")).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(app_data.rooms.lock().await.contains_key("a"));
+    }
+
+    /// Returns the body of the room list requested with `query`.
+    async fn room_list(app_data: &SharedAppData, query: &str) -> String {
+        room_list_with_headers(app_data, query, "").await
+    }
+
+    /// Like [room_list], with `headers` that have to end with a line break each.
+    async fn room_list_with_headers(
+        app_data: &SharedAppData,
+        query: &str,
+        headers: &str,
+    ) -> String {
+        let raw = format!("GET /api/rooms{} HTTP/1.1\r\n{}\r\n", query, headers);
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        let response = response_string(handle(&request, app_data).await);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        response.split("\r\n\r\n").nth(1).unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_room_list_pagination() {
+        let app_data = app_data_with_rooms(&["e", "b", "d", "a", "c"]);
+        assert_eq!(
+            room_list(&app_data, "").await,
+            r#"{"total":5,"rooms":["a","b","c","d","e"]}"#
+        );
+        assert_eq!(
+            room_list(&app_data, "?limit=2&offset=1").await,
+            r#"{"total":5,"rooms":["b","c"]}"#
+        );
+        assert_eq!(
+            room_list(&app_data, "?offset=4&limit=2").await,
+            r#"{"total":5,"rooms":["e"]}"#
+        );
+        assert_eq!(
+            room_list(&app_data, "?offset=9").await,
+            r#"{"total":5,"rooms":[]}"#
+        );
+
+        let raw = b"GET /api/rooms?limit=many HTTP/1.1\r\n\r\n";
+        let request = Request::try_parse_from(&raw[..]).await.unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_room_list_filtering() {
+        let app_data = app_data_with_rooms(&["team-a", "team-b", "other", "team-secret"]);
+        app_data
+            .lock_room("team-secret")
+            .await
+            .unwrap()
+            .options
+            .password = Some(String::from("hunter2"));
+        assert_eq!(
+            room_list(&app_data, "?prefix=team-").await,
+            r#"{"total":2,"rooms":["team-a","team-b"]}"#
+        );
+        assert_eq!(
+            room_list(&app_data, "?prefix=team-&limit=1").await,
+            r#"{"total":2,"rooms":["team-a"]}"#
+        );
+        // guessing the password doesn't reveal the room
+        assert_eq!(
+            room_list(&app_data, "?prefix=team-s&password=hunter2").await,
+            r#"{"total":0,"rooms":[]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_room_list_shows_protected_rooms_to_admins() {
+        let app_data = Arc::new(AppData::new(Config {
+            admin_token: Some(String::from("secret")),
+            ..Config::default()
+        }));
+        for name in ["a", "b"] {
+            let mut room = RoomData::default();
+            if name == "b" {
+                room.options.password = Some(String::from("hunter2"));
+            }
+            let room = Arc::new(Mutex::new(room));
+            app_data.rooms.lock().await.insert(name.to_owned(), room);
+        }
+        assert_eq!(
+            room_list(&app_data, "").await,
+            r#"{"total":1,"rooms":["a"]}"#
+        );
+        assert_eq!(
+            room_list_with_headers(&app_data, "", "Authorization: Bearer guess\r\n").await,
+            r#"{"total":1,"rooms":["a"]}"#
+        );
+        assert_eq!(
+            room_list_with_headers(&app_data, "", "Authorization: Bearer secret\r\n").await,
+            r#"{"total":2,"rooms":["a","b"]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_room_members() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let (first, _first_client) = socket_pair().await;
+        let (second, _second_client) = socket_pair().await;
+        {
+            let mut room = app_data.lock_room("a").await.unwrap();
+            room.sockets.insert(3, Member::new(first, &app_data.config));
+            room.sockets
+                .insert(1, Member::new(second, &app_data.config));
+        }
+        let members = |name: &'static str| {
+            let app_data = Arc::clone(&app_data);
+            async move {
+                let raw = format!("GET /api/rooms/{}/members HTTP/1.1\r\n\r\n", name);
+                let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+                response_string(handle(&request, &app_data).await)
+            }
+        };
+
+        let response = members("a").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"[{"id":1},{"id":3}]"#));
+
+        app_data.lock_room("a").await.unwrap().sockets.remove(&3);
+        assert!(members("a").await.ends_with(r#"[{"id":1}]"#));
+
+        let response = members("b").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        for (event, json) in [
+            (
+                ControlMessage::Join(MemberInfo {
+                    id: 3,
+                    nick: Some(String::from("bob")),
+                }),
+                r#"{"type":"join","id":3,"nick":"bob"}"#,
+            ),
+            (
+                ControlMessage::Leave(MemberInfo { id: 4, nick: None }),
+                r#"{"type":"leave","id":4}"#,
+            ),
+            (
+                ControlMessage::System {
+                    message: String::from("restarting soon"),
+                },
+                r#"{"type":"system","message":"restarting soon"}"#,
+            ),
+            (
+                ControlMessage::Error {
+                    message: String::from("no"),
+                },
+                r#"{"type":"error","message":"no"}"#,
+            ),
+        ] {
+            let message = event.to_message();
+            assert!(
+                matches!(&message, Message::Text(text) if text == json),
+                "{}",
+                json
+            );
+            assert_eq!(ControlMessage::parse(&message), Some(event));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_events() {
+        let app_data = Arc::new(AppData::new(Config {
+            member_events: true,
+            ..Config::default()
+        }));
+        let room = Arc::new(Mutex::new(RoomData::default()));
+        app_data.rooms.lock().await.insert(String::from("a"), room);
+
+        let mut alice = connect(&app_data, "/ws?room=a&nick=alice").await;
+        read_welcome(&mut alice).await;
+        let mut bob = connect(&app_data, "/ws?room=a&nick=bob").await;
+        let (bob_id, _) = read_welcome(&mut bob).await;
+        let bob_info = MemberInfo {
+            id: bob_id,
+            nick: Some(String::from("bob")),
+        };
+        assert_eq!(
+            read_control(&mut alice).await,
+            Some(ControlMessage::Join(bob_info.clone()))
+        );
+
+        drop(bob);
+        let mut room = app_data.lock_room("a").await.unwrap();
+        while room.sockets.len() > 1 {
+            relay_room_messages("a", &mut room, &app_data).await;
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            read_control(&mut alice).await,
+            Some(ControlMessage::Leave(bob_info))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_member_list_broadcast_on_leave() {
+        let app_data = AppData::new(Config {
+            broadcast_members: true,
+            ..Config::default()
+        });
+        let (staying, mut staying_client) = socket_pair().await;
+        let (leaving, leaving_client) = socket_pair().await;
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(staying, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(leaving, &app_data.config));
+
+        drop(leaving_client);
+        relay_until(&mut room, &app_data, |room| room.sockets.len() == 1).await;
+        assert_eq!(room.sockets.len(), 1);
+
+        let expected = br#"{"type":"members","members":[{"id":0}]}"#;
+        let mut buf = vec![0; 2 + expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), staying_client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[..2], [0x81, expected.len() as u8]);
+        assert_eq!(&buf[2..], expected);
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let app_data = app_data_with_rooms(&[]);
+        for (request, allow) in [
+            (&b"POST /api/rooms HTTP/1.1\r\n\r\n"[..], "GET"),
+            (b"PUT /api/rooms/a HTTP/1.1\r\n\r\n", "DELETE"),
+            (b"DELETE /api/gen-room HTTP/1.1\r\n\r\n", "GET, POST"),
+        ] {
+            let request = Request::try_parse_from(request).await.unwrap();
+            let response = response_string(handle(&request, &app_data).await);
+            assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+            assert!(response.contains(&format!("\r\nallow: {}\r\n", allow)));
+        }
+
+        let request = Request::try_parse_from(&b"POST /missing HTTP/1.1\r\n\r\n"[..])
+            .await
+            .unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_not_found_error_body() {
+        let app_data = app_data_with_rooms(&[]);
+        let request = Request::try_parse_from(&b"GET /missing HTTP/1.1\r\n\r\n"[..])
+            .await
+            .unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.contains("\r\ncontent-type: text/plain; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\nError 404: no resource with path /missing found"));
+
+        let raw = b"GET /missing HTTP/1.1\r\nAccept: text/html, application/json;q=0.9\r\n\r\n";
+        let request = Request::try_parse_from(&raw[..]).await.unwrap();
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.contains("\r\ncontent-type: application/json; charset=utf-8\r\n"));
+        assert!(
+            response.ends_with(r#"{"status":404,"error":"no resource with path /missing found"}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_static_file_content_types() {
+        let app_data = app_data_with_rooms(&[]);
+        for (path, content_type) in [
+            ("/index.html", "text/html; charset=utf-8"),
+            ("/chat?room=a", "text/html; charset=utf-8"),
+            ("/scripts/chat.js", "text/javascript; charset=utf-8"),
+            ("/styles/style.css", "text/css; charset=utf-8"),
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+            let response = response_string(handle(&request, &app_data).await);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", path);
+            let header = format!("\r\ncontent-type: {}\r\n", content_type);
+            assert!(response.contains(&header), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_missing_room() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let response = delete_room(&app_data, "b").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_room_with_members_conflicts() {
+        let app_data = app_data_with_rooms(&["a"]);
+        let (socket, _client) = socket_pair().await;
+        app_data
+            .lock_room("a")
+            .await
+            .unwrap()
+            .sockets
+            .insert(0, Member::new(socket, &app_data.config));
+        let response = delete_room(&app_data, "a").await;
+        assert!(response.starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(app_data.rooms.lock().await.contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_permanent_room_forbidden() {
+        let app_data = Arc::new(AppData::default());
+        app_data.rooms.lock().await.insert(
+            config::DEFAULT_ROOM_NAME.to_owned(),
+            RoomData::with_options(RoomOptions {
+                permanent: true,
+                ..RoomOptions::default()
+            })
+            .spawn_shared(config::DEFAULT_ROOM_NAME, &app_data),
+        );
+        let response = delete_room(&app_data, config::DEFAULT_ROOM_NAME).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(app_data
+            .rooms
+            .lock()
+            .await
+            .contains_key(config::DEFAULT_ROOM_NAME));
+    }
+
+    #[tokio::test]
+    async fn test_create_duplicate_room_conflicts() {
+        let app_data = app_data_with_rooms(&["lobby"]);
+        let request = gen_room_request(r#"{"name":"lobby"}"#).await;
+        let response = response_string(handle(&request, &app_data).await);
+        assert!(response.starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(response.ends_with(r#"{"status":1,"message":"Room already exists."}"#));
+        assert_eq!(app_data.rooms.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_supported_version() {
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.room, "a");
+        let response = response_string(upgrade.response);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_multi_valued_connection() {
+        let raw = "GET /ws?room=a HTTP/1.1\r\n\
+                   Connection: keep-alive, Upgrade\r\n\
+                   Upgrade: WebSocket\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                   Sec-WebSocket-Version: 13\r\n\r\n";
+        let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+        assert!(try_upgrade_to_ws(&request, &Config::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ws_requirements_connection_tokens() {
+        for (connection, fulfilled) in [
+            ("Upgrade", true),
+            ("keep-alive, Upgrade", true),
+            ("keep-alive", false),
+        ] {
+            let raw = format!(
+                "GET /ws?room=a HTTP/1.1\r\n\
+                 Connection: {}\r\n\
+                 Upgrade: websocket\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                connection
+            );
+            let request = Request::try_parse_from(raw.as_bytes()).await.unwrap();
+            assert_eq!(
+                fulfills_ws_requirements(&request),
+                fulfilled,
+                "{}",
+                connection
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_without_version() {
+        let request = ws_request("").await;
+        let response =
+            response_string(try_upgrade_to_ws(&request, &Config::default()).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("sec-websocket-version: 13\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_unsupported_version() {
+        let request = ws_request("Sec-WebSocket-Version: 8\r\n").await;
+        let response =
+            response_string(try_upgrade_to_ws(&request, &Config::default()).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("sec-websocket-version: 13\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_valid_key() {
+        let key = "AQIDBAUGBwgJCgsMDQ4PEA==";
+        assert_eq!(key.len(), 24);
+        let request = ws_request_with_key(key, "Sec-WebSocket-Version: 13\r\n").await;
+        assert!(try_upgrade_to_ws(&request, &Config::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_short_key() {
+        let request = ws_request_with_key("AQIDBAUGBwg=", "Sec-WebSocket-Version: 13\r\n").await;
+        let response =
+            response_string(try_upgrade_to_ws(&request, &Config::default()).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_with_malformed_key() {
+        let request = ws_request_with_key("not base64!", "Sec-WebSocket-Version: 13\r\n").await;
+        let response =
+            response_string(try_upgrade_to_ws(&request, &Config::default()).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_origin_allowlist() {
+        let config = Config {
+            allowed_origins: vec![String::from("https://morse.example")],
+            ..Config::default()
+        };
+        let request =
+            ws_request("Sec-WebSocket-Version: 13\r\nOrigin: https://morse.example\r\n").await;
+        assert!(try_upgrade_to_ws(&request, &config).is_ok());
+
+        let request =
+            ws_request("Sec-WebSocket-Version: 13\r\nOrigin: https://evil.example\r\n").await;
+        let response = response_string(try_upgrade_to_ws(&request, &config).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let response = response_string(try_upgrade_to_ws(&request, &config).unwrap_err());
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_without_origin_allowlist() {
+        let request =
+            ws_request("Sec-WebSocket-Version: 13\r\nOrigin: https://evil.example\r\n").await;
+        assert!(try_upgrade_to_ws(&request, &Config::default()).is_ok());
+    }
+
+    #[test]
+    fn test_sanitize_nick() {
+        assert_eq!(
+            sanitize_nick("bob_the-2nd"),
+            Some(String::from("bob_the-2nd"))
+        );
+        assert_eq!(
+            sanitize_nick("<b>bob</b>%20"),
+            Some(String::from("bbobb20"))
+        );
+        assert_eq!(
+            sanitize_nick(&"a".repeat(100)),
+            Some("a".repeat(MAX_NICK_LEN))
+        );
+        assert_eq!(sanitize_nick("<>/"), None);
+        assert_eq!(sanitize_nick(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_extracts_nick() {
+        let request = ws_request_to(
+            "/ws?room=a&nick=<bob>",
+            TEST_KEY,
+            "Sec-WebSocket-Version: 13\r\n",
+        )
+        .await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.room, "a");
+        assert_eq!(upgrade.nick.as_deref(), Some("bob"));
+
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.nick, None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_nicks_get_suffix() {
+        let config = Config::default();
+        let long = "a".repeat(MAX_NICK_LEN);
+        let mut room = RoomData::default();
+        for (id, nick) in [(0, "bob"), (1, "bob-2"), (2, long.as_str())] {
+            let (socket, _client) = socket_pair().await;
+            let mut member = Member::new(socket, &config);
+            member.nick = Some(nick.to_owned());
+            room.sockets.insert(id, member);
+        }
+        assert_eq!(room.unique_nick(String::from("alice")), "alice");
+        assert_eq!(room.unique_nick(String::from("bob")), "bob-3");
+        let unique = room.unique_nick(long.clone());
+        assert_eq!(unique, format!("{}-2", &long[..MAX_NICK_LEN - 2]));
+
+        let (socket, _client) = socket_pair().await;
+        room.sockets.insert(3, Member::new(socket, &config));
+        assert_eq!(
+            serde_json::to_string(&room.member_list()).unwrap(),
+            format!(
+                r#"[{{"id":0,"nick":"bob"}},{{"id":1,"nick":"bob-2"}},{{"id":2,"nick":"{}"}},{{"id":3}}]"#,
+                long
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_subprotocol() {
+        let supported = vec![String::from("morse"), String::from("chat")];
+        assert_eq!(
+            select_subprotocol("superchat, chat, morse", &supported),
+            Some("chat")
+        );
+        assert_eq!(select_subprotocol("morse", &supported), Some("morse"));
+        assert_eq!(select_subprotocol("superchat", &supported), None);
+        assert_eq!(select_subprotocol("morse", &[]), None);
+    }
+
+    #[test]
+    fn test_select_extensions() {
+        let offered = "permessage-deflate; client_max_window_bits, x-webkit-deflate-frame, \
+                       permessage-deflate";
+        assert!(select_extensions(offered, &[]).is_empty());
+        assert_eq!(
+            select_extensions(offered, &["permessage-deflate"]),
+            ["permessage-deflate"]
+        );
+        assert_eq!(
+            select_extensions(offered, &["x-webkit-deflate-frame", "permessage-deflate"]),
+            ["permessage-deflate", "x-webkit-deflate-frame"]
+        );
+        assert!(select_extensions("", &["permessage-deflate"]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_without_supported_extensions() {
+        let headers = "Sec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n";
+        let request = ws_request(headers).await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert!(upgrade.extensions.is_empty());
+        let response = response_string(upgrade.response);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(!response.contains("sec-websocket-extensions"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_echoes_subprotocol() {
+        let headers = "Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: v2.morse, morse\r\n";
+        let request = ws_request(headers).await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.protocol.as_deref(), Some("morse"));
+        let response = response_string(upgrade.response);
+        assert!(response.contains("sec-websocket-protocol: morse\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_without_subprotocol() {
+        let request = ws_request("Sec-WebSocket-Version: 13\r\n").await;
+        let upgrade = try_upgrade_to_ws(&request, &Config::default()).unwrap();
+        assert_eq!(upgrade.protocol, None);
+        let response = response_string(upgrade.response);
+        assert!(!response.contains("sec-websocket-protocol"));
+    }
+
+    #[test]
+    fn test_json_subscriber() {
+        let subscriber = build_subscriber(&Config {
+            log_format: LogFormat::Json,
+            ..Config::default()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            info!(room_name = "a", "logged as json.");
+        });
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_removes_peer() {
+        let app_data = AppData::default();
+        let (sender, mut sender_client) = socket_pair().await;
+        let (closed, mut closed_client) = socket_pair().await;
+
+        // close the connection and consume the resulting error, so the peer is
+        // only noticed as gone when sending to it
+        closed_client
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8])
+            .await
+            .unwrap();
+        loop {
+            match closed.poll_next_message().await {
+                Some(Err(_)) => break,
+                _ => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+
+        let mut room = RoomData::default();
+        room.sockets
+            .insert(0, Member::new(sender, &app_data.config));
+        room.sockets
+            .insert(1, Member::new(closed, &app_data.config));
+        sender_client
+            .write_all(&client_text_frame("hi"))
+            .await
+            .unwrap();
+        relay_until(&mut room, &app_data, |room| !room.sockets.contains_key(&1)).await;
+        assert!(room.sockets.contains_key(&0));
+        assert!(!room.sockets.contains_key(&1));
+    }
 }
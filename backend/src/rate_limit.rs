@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::Instant;
+
+/// A token bucket allowing `rate` actions per second on average with bursts of
+/// up to `capacity` actions.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(rate: f64, capacity: u32) -> Self {
+        Self {
+            rate,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token from the bucket if there is one. Returns `false` if the
+    /// action should be rejected.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits the number of open connections per source address.
+#[derive(Debug, Clone)]
+pub struct IpConnectionLimit {
+    max_per_ip: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Counts a connection against its address until dropped.
+#[derive(Debug)]
+pub struct IpConnectionGuard {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpConnectionLimit {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Counts a new connection from `ip`. Returns `None` if the address
+    /// already has the maximum number of connections open.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpConnectionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnectionGuard {
+            ip,
+            counts: Arc::clone(&self.counts),
+        })
+    }
+
+    /// Returns the number of open connections from `ip`.
+    pub fn count(&self, ip: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+impl IpConnectionGuard {
+    /// Returns the address the connection is counted against.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_is_limited() {
+        let mut bucket = TokenBucket::new(1.0, 3);
+        let now = bucket.last_refill;
+        assert!(bucket.try_take_at(now));
+        assert!(bucket.try_take_at(now));
+        assert!(bucket.try_take_at(now));
+        assert!(!bucket.try_take_at(now));
+    }
+
+    #[test]
+    fn test_refill() {
+        let mut bucket = TokenBucket::new(2.0, 1);
+        let now = bucket.last_refill;
+        assert!(bucket.try_take_at(now));
+        assert!(!bucket.try_take_at(now + Duration::from_millis(100)));
+        assert!(bucket.try_take_at(now + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn test_refill_is_capped() {
+        let mut bucket = TokenBucket::new(10.0, 2);
+        let later = bucket.last_refill + Duration::from_secs(60);
+        assert!(bucket.try_take_at(later));
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+    }
+
+    #[test]
+    fn test_connections_limited_per_ip() {
+        let limit = IpConnectionLimit::new(2);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let first = limit.try_acquire(ip).unwrap();
+        let _second = limit.try_acquire(ip).unwrap();
+        assert!(limit.try_acquire(ip).is_none());
+        assert!(limit.try_acquire(IpAddr::from([10, 0, 0, 2])).is_some());
+
+        drop(first);
+        assert_eq!(limit.count(ip), 1);
+        assert!(limit.try_acquire(ip).is_some());
+    }
+}
@@ -1,5 +1,8 @@
 use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
 
 use crate::HeaderName;
 
@@ -9,6 +12,27 @@ pub struct Request {
     path: String,
     version: String,
     headers: HashMap<HeaderName, String>,
+    body: Vec<u8>,
+}
+
+/// Size limits applied while parsing a request.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum length in bytes of the request line.
+    pub max_request_line: usize,
+    /// Maximum number of header lines.
+    pub max_header_count: usize,
+    /// Maximum combined length in bytes of all header lines.
+    pub max_header_bytes: usize,
+    /// Maximum length in bytes of the body, as announced by `Content-Length`.
+    pub max_body_bytes: usize,
+}
+
+/// HTTP versions the server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,10 +45,21 @@ pub enum Method {
 
 impl Request {
     pub async fn try_parse_from<R: AsyncReadExt + Unpin>(src: R) -> anyhow::Result<Self> {
-        let mut r = BufReader::new(src);
+        Self::try_parse_with_limits(BufReader::new(src), &Limits::default()).await
+    }
 
+    /// Like [Request::try_parse_from], but fails with a [LimitError] if the
+    /// request exceeds the given limits. Reads from a buffered reader, so
+    /// anything after the request, e.g. the next request on a keep-alive
+    /// connection, stays in the reader.
+    pub async fn try_parse_with_limits<R: AsyncBufRead + Unpin>(
+        mut r: R,
+        limits: &Limits,
+    ) -> anyhow::Result<Self> {
         let mut first_line = String::new();
-        r.read_line(&mut first_line).await?;
+        read_line_limited(&mut r, &mut first_line, limits.max_request_line)
+            .await?
+            .ok_or(LimitError("request line too long"))?;
         let mut first_line_split = first_line.split(' ');
         let method = first_line_split
             .next()
@@ -48,12 +83,19 @@ impl Request {
         };
 
         let mut headers = HashMap::new();
+        let mut header_bytes = 0;
         loop {
             let mut line = String::new();
-            r.read_line(&mut line).await?;
+            let remaining = limits.max_header_bytes - header_bytes;
+            header_bytes += read_line_limited(&mut r, &mut line, remaining)
+                .await?
+                .ok_or(LimitError("headers too large"))?;
             if line.trim().is_empty() {
                 break;
             }
+            if headers.len() >= limits.max_header_count {
+                return Err(LimitError("too many headers").into());
+            }
 
             let (name, value) = line
                 .split_once(':')
@@ -61,11 +103,24 @@ impl Request {
             headers.insert(HeaderName::from_str(name), value.trim().to_owned());
         }
 
+        let mut body = Vec::new();
+        if let Some(len) = headers.get(&HeaderName::from_str("content-length")) {
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| ParseError("expected numeric content-length"))?;
+            if len > limits.max_body_bytes {
+                return Err(LimitError("body too large").into());
+            }
+            body.resize(len, 0);
+            r.read_exact(&mut body).await?;
+        }
+
         let req = Self {
             method,
             path,
             version,
             headers,
+            body,
         };
         Ok(req)
     }
@@ -82,22 +137,118 @@ impl Request {
         &self.version
     }
 
+    /// Returns the HTTP version of the request, or `None` if the server
+    /// doesn't speak it.
+    pub fn remote_version(&self) -> Option<Version> {
+        match self.version.as_str() {
+            "HTTP/1.0" => Some(Version::Http10),
+            "HTTP/1.1" => Some(Version::Http11),
+            _ => None,
+        }
+    }
+
     pub fn headers(&self) -> &HashMap<HeaderName, String> {
         &self.headers
     }
+
+    /// Returns the value of the header `name`, which is matched ignoring
+    /// ASCII case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        // header names are stored lowercase, so most lookups need no copy
+        let value = if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            self.headers.get(&HeaderName::from_str(name))
+        } else {
+            self.headers.get(name)
+        };
+        value.map(String::as_str)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("content-type")
+    }
+
+    /// Returns the `Content-Length`, or `None` if it is missing or not a
+    /// number.
+    pub fn content_length(&self) -> Option<usize> {
+        self.header("content-length")?.parse().ok()
+    }
+
+    /// Returns whether the comma separated header `name` contains `token`,
+    /// see [crate::contains_token].
+    pub fn header_contains_token(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|value| crate::contains_token(value, token))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Deserializes the body as JSON. Fails if the request isn't declared as
+    /// `application/json` or has no body.
+    pub fn json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let content_type = self
+            .content_type()
+            .ok_or(ParseError("expected content-type application/json"))?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/json") {
+            return Err(ParseError("expected content-type application/json").into());
+        }
+        if self.body.is_empty() {
+            return Err(ParseError("expected JSON body").into());
+        }
+        serde_json::from_slice(&self.body).context("invalid JSON body")
+    }
+}
+
+/// Reads a line of at most `limit` bytes. Returns the number of bytes read, or
+/// `None` if the line is longer than that.
+async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    buf: &mut String,
+    limit: usize,
+) -> anyhow::Result<Option<usize>> {
+    let n = r.take(limit as u64).read_line(buf).await?;
+    if n == limit && !buf.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(n))
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_count: 100,
+            max_header_bytes: 16 * 1024,
+            max_body_bytes: 64 * 1024,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct ParseError(&'static str);
 
+/// Returned when a request exceeds the configured [Limits].
+#[derive(Clone, Copy, Debug)]
+pub struct LimitError(&'static str);
+
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self.0)
     }
 }
 
 impl std::error::Error for ParseError {}
 
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitError {}
+
 impl std::fmt::Display for Method {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
@@ -108,3 +259,156 @@ impl std::fmt::Display for Method {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_get() -> anyhow::Result<()> {
+        let data = b"GET /chat?room=a HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert_eq!(req.method(), Method::Get);
+        assert_eq!(req.path(), "/chat?room=a");
+        assert_eq!(req.version(), "HTTP/1.1");
+        assert_eq!(req.header("host"), Some("localhost"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_version() -> anyhow::Result<()> {
+        let versions = [
+            ("HTTP/1.0", Some(Version::Http10)),
+            ("HTTP/1.1", Some(Version::Http11)),
+            ("HTTP/2.0", None),
+            ("HTTP/1.1.1", None),
+        ];
+        for (version, expected) in versions {
+            let data = format!("GET / {}\r\n\r\n", version);
+            let req = Request::try_parse_from(data.as_bytes()).await?;
+            assert_eq!(req.remote_version(), expected, "{}", version);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_body() -> anyhow::Result<()> {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct NewRoom {
+            name: String,
+        }
+
+        let data = b"POST /api/rooms HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: 16\r\n\r\n{\"name\":\"lobby\"}GET";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert_eq!(req.body(), b"{\"name\":\"lobby\"}");
+        let room = req.json::<NewRoom>()?;
+        assert_eq!(
+            room,
+            NewRoom {
+                name: "lobby".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_rejects_wrong_content_type_and_empty_body() -> anyhow::Result<()> {
+        let data = b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\n{}";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert!(req
+            .json::<HashMap<String, String>>()
+            .unwrap_err()
+            .is::<ParseError>());
+
+        let data = b"POST / HTTP/1.1\r\nContent-Type: application/json\r\n\r\n";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert!(req
+            .json::<HashMap<String, String>>()
+            .unwrap_err()
+            .is::<ParseError>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let data = b"POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n";
+        let err = Request::try_parse_from(&data[..]).await.unwrap_err();
+        assert!(err.is::<LimitError>());
+    }
+
+    #[tokio::test]
+    async fn test_content_accessors() -> anyhow::Result<()> {
+        let data = b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert_eq!(req.content_type(), Some("text/plain"));
+        assert_eq!(req.content_length(), Some(2));
+
+        let req = Request::try_parse_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).await?;
+        assert_eq!(req.content_type(), None);
+        assert_eq!(req.content_length(), None);
+
+        let mut req = Request::try_parse_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).await?;
+        req.headers
+            .insert(HeaderName::from_str("content-length"), "two".into());
+        assert_eq!(req.content_length(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_header() -> anyhow::Result<()> {
+        let data = b"GET / HTTP/1.1\r\nX-Api-Key: abc\r\nACCEPT: text/html\r\n\r\n";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert_eq!(req.header("x-api-key"), Some("abc"));
+        assert_eq!(req.header("X-API-KEY"), Some("abc"));
+        assert_eq!(req.header("Accept"), Some("text/html"));
+        assert_eq!(req.header("origin"), None);
+        assert_eq!(req.header(""), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_header_contains_token() -> anyhow::Result<()> {
+        let data = b"GET / HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\n\r\n";
+        let req = Request::try_parse_from(&data[..]).await?;
+        assert!(req.header_contains_token("connection", "upgrade"));
+        assert!(req.header_contains_token("Connection", "keep-alive"));
+        assert!(!req.header_contains_token("connection", "close"));
+        assert!(!req.header_contains_token("upgrade", "websocket"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_too_many_headers() {
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..5000 {
+            data.extend_from_slice(format!("x-header-{}: {}\r\n", i, i).as_bytes());
+        }
+        data.extend_from_slice(b"\r\n");
+        let limits = Limits {
+            max_header_bytes: usize::MAX,
+            ..Limits::default()
+        };
+        let err = Request::try_parse_with_limits(&data[..], &limits)
+            .await
+            .unwrap_err();
+        assert!(err.is::<LimitError>());
+    }
+
+    #[tokio::test]
+    async fn test_headers_too_large() {
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..5000 {
+            data.extend_from_slice(format!("x-header-{}: {}\r\n", i, i).as_bytes());
+        }
+        data.extend_from_slice(b"\r\n");
+        let err = Request::try_parse_from(&data[..]).await.unwrap_err();
+        assert!(err.is::<LimitError>());
+    }
+
+    #[tokio::test]
+    async fn test_request_line_too_long() {
+        let data = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000));
+        let err = Request::try_parse_from(data.as_bytes()).await.unwrap_err();
+        assert!(err.is::<LimitError>());
+    }
+}
@@ -1,24 +1,53 @@
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use tokio::io::AsyncWriteExt;
+use anyhow::bail;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
+use crate::request::{LimitError, ParseError};
 use crate::HeaderName;
 
+const SERVER: &str = concat!("morse-chat/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
 pub struct Response {
     status: Status,
     headers: HashMap<HeaderName, String>,
     body: Vec<u8>,
+    /// Streams the body instead of `body`, see [Builder::with_body_reader].
+    body_reader: Option<BodyReader>,
+}
+
+/// A body that is read while the response is written, so it never has to be
+/// in memory as a whole.
+struct BodyReader {
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    len: u64,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Status {
     SwitchingProtocols,
     #[default]
     OK,
+    NoContent,
+    MovedPermanently,
+    Found,
     BadRequest,
+    Unauthorized,
     Forbidden,
     NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
     InternalServerError,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
 }
 
 #[derive(Default)]
@@ -32,12 +61,102 @@ impl Response {
         Default::default()
     }
 
-    pub async fn try_write_to<W: AsyncWriteExt + Unpin>(self, mut dest: W) -> anyhow::Result<()> {
-        dest.write(&self.into_bytes()).await?;
+    /// Returns a response with an empty body redirecting the client to
+    /// `location`.
+    pub fn redirect(status: Status, location: &str) -> Self {
+        Self::builder()
+            .with_status(status)
+            .with_header("location", location)
+            .with_body(Vec::new())
+    }
+
+    /// Returns a response with `status` whose body explains why, as json if
+    /// `as_json` is set and as plain text otherwise.
+    pub fn error(status: Status, message: &str, as_json: bool) -> Self {
+        let mut builder = Self::builder();
+        builder.with_status(status);
+        if as_json {
+            builder.with_json(&ErrorBody {
+                status: status.code(),
+                error: message,
+            })
+        } else {
+            builder
+                .as_text()
+                .with_body(format!("Error {}: {}", status.code(), message))
+        }
+    }
+
+    /// Builds the response to a request that failed with `error`. Errors the
+    /// client caused, like a [ParseError], get a 4xx status and say what was
+    /// wrong. Anything else is a 500 with a generic message, so internal
+    /// details don't leak. The body is plain text, as the `Accept` header of a
+    /// request that couldn't be parsed isn't known.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let is_malformed = error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof
+            )
+        });
+        let (status, message) = if let Some(e) = error.downcast_ref::<LimitError>() {
+            (Status::RequestHeaderFieldsTooLarge, e.to_string())
+        } else if let Some(e) = error.downcast_ref::<ParseError>() {
+            (Status::BadRequest, e.to_string())
+        } else if error.downcast_ref::<serde_json::Error>().is_some() {
+            (Status::BadRequest, String::from("invalid JSON body"))
+        } else if is_malformed {
+            (Status::BadRequest, String::from("malformed request"))
+        } else {
+            (
+                Status::InternalServerError,
+                String::from("internal server error"),
+            )
+        };
+        Self::error(status, &message, false)
+    }
+
+    /// Writes the response to `dest`, streaming a body set with
+    /// [Builder::with_body_reader]. Fails if that body ends early, as the
+    /// announced `Content-Length` can't be met anymore.
+    pub async fn try_write_to<W: AsyncWriteExt + Unpin>(
+        mut self,
+        mut dest: W,
+    ) -> anyhow::Result<()> {
+        let body_reader = self.body_reader.take();
+        dest.write_all(&self.into_bytes()).await?;
+        if let Some(BodyReader { reader, len }) = body_reader {
+            let written = tokio::io::copy(&mut reader.take(len), &mut dest).await?;
+            if written < len {
+                bail!("response body ended after {} of {} bytes", written, len);
+            }
+        }
         Ok(())
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
+    /// Sets a header, replacing any previous value.
+    pub fn set_header<N: AsRef<str>, V: Into<String>>(&mut self, name: N, value: V) {
+        self.headers
+            .insert(HeaderName::from_str(name.as_ref()), value.into());
+    }
+
+    /// Serializes the response. `Date`, `Server` and, except for interim and
+    /// 204 responses, `Content-Length` headers are added unless the handler
+    /// already set them. A body set with [Builder::with_body_reader] is left
+    /// out, only [Response::try_write_to] streams it.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.headers
+            .entry(HeaderName::from_str("date"))
+            .or_insert_with(|| http_date(SystemTime::now()));
+        self.headers
+            .entry(HeaderName::from_str("server"))
+            .or_insert_with(|| String::from(SERVER));
+        if !matches!(self.status, Status::SwitchingProtocols | Status::NoContent) {
+            self.headers
+                .entry(HeaderName::from_str("content-length"))
+                .or_insert_with(|| self.body.len().to_string());
+        }
+
         let first_line = format!("HTTP/1.1 {}\r\n", self.status.as_str());
         let headers = self
             .headers
@@ -53,15 +172,96 @@ impl Response {
     }
 }
 
+/// Formats `time` as an IMF-fixdate (RFC 7231), e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = (shifted_month + 2) % 12;
+    let year = year_of_era + era * 400 + u64::from(month < 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Body of error responses to clients that accept json, see
+/// [Response::error].
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+impl From<ParseError> for Response {
+    fn from(error: ParseError) -> Self {
+        Self::from_error(&error.into())
+    }
+}
+
+impl std::fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyReader")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Status {
+    /// Returns the numeric status code, e.g. 404.
+    pub fn code(self) -> u16 {
+        self.as_str()[..3]
+            .parse()
+            .expect("status strings start with the code")
+    }
+
     pub fn as_str(self) -> &'static str {
         match self {
             Self::SwitchingProtocols => "101 Switching Protocols",
             Self::OK => "200 OK",
+            Self::NoContent => "204 No Content",
+            Self::MovedPermanently => "301 Moved Permanently",
+            Self::Found => "302 Found",
             Self::BadRequest => "400 Bad Request",
+            Self::Unauthorized => "401 Unauthorized",
             Self::Forbidden => "403 Forbidden",
             Self::NotFound => "404 Not Found",
+            Self::MethodNotAllowed => "405 Method Not Allowed",
+            Self::RequestTimeout => "408 Request Timeout",
+            Self::Conflict => "409 Conflict",
+            Self::UpgradeRequired => "426 Upgrade Required",
+            Self::TooManyRequests => "429 Too Many Requests",
+            Self::RequestHeaderFieldsTooLarge => "431 Request Header Fields Too Large",
             Self::InternalServerError => "500 Internal Server Error",
+            Self::ServiceUnavailable => "503 Service Unavailable",
+            Self::HttpVersionNotSupported => "505 HTTP Version Not Supported",
         }
     }
 }
@@ -73,19 +273,23 @@ impl Builder {
     }
 
     pub fn as_css(&mut self) -> &mut Self {
-        self.with_header("content-type", "text/css")
+        self.with_header("content-type", "text/css; charset=utf-8")
     }
 
     pub fn as_js(&mut self) -> &mut Self {
-        self.with_header("content-type", "text/javascript")
+        self.with_header("content-type", "text/javascript; charset=utf-8")
     }
 
     pub fn as_html(&mut self) -> &mut Self {
-        self.with_header("content-type", "text/html")
+        self.with_header("content-type", "text/html; charset=utf-8")
+    }
+
+    pub fn as_text(&mut self) -> &mut Self {
+        self.with_header("content-type", "text/plain; charset=utf-8")
     }
 
     pub fn as_json(&mut self) -> &mut Self {
-        self.with_header("content-type", "application/json")
+        self.with_header("content-type", "application/json; charset=utf-8")
     }
 
     pub fn with_header<N: AsRef<str>, V: Into<String>>(&mut self, name: N, value: V) -> &mut Self {
@@ -94,11 +298,277 @@ impl Builder {
         self
     }
 
+    /// Sets every header in `headers`, replacing previous values.
+    pub fn with_headers<I, N, V>(&mut self, headers: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (N, V)>,
+        N: AsRef<str>,
+        V: Into<String>,
+    {
+        for (name, value) in headers {
+            self.with_header(name, value);
+        }
+        self
+    }
+
+    pub fn remove_header<N: AsRef<str>>(&mut self, name: N) -> &mut Self {
+        self.headers.remove(&HeaderName::from_str(name.as_ref()));
+        self
+    }
+
     pub fn with_body<B: Into<Vec<u8>>>(&mut self, body: B) -> Response {
         Response {
             status: self.status,
             headers: self.headers.clone(),
             body: body.into(),
+            body_reader: None,
         }
     }
+
+    /// Streams the body from `reader` when the response is written, instead
+    /// of holding it in memory, e.g. for large files. `len` is sent as the
+    /// `Content-Length`, so exactly that many bytes are read.
+    pub fn with_body_reader<R>(&mut self, reader: R, len: u64) -> Response
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let mut headers = self.headers.clone();
+        headers.insert(HeaderName::from_str("content-length"), len.to_string());
+        Response {
+            status: self.status,
+            headers,
+            body: Vec::new(),
+            body_reader: Some(BodyReader {
+                reader: Box::pin(reader),
+                len,
+            }),
+        }
+    }
+
+    /// Serializes `value` as the JSON body and sets the content type. Falls
+    /// back to an empty 500 response if serialization fails.
+    pub fn with_json<T: Serialize>(&mut self, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => self.as_json().with_body(body),
+            Err(_) => self
+                .with_status(Status::InternalServerError)
+                .with_body(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_json() {
+        #[derive(Serialize)]
+        struct Room<'a> {
+            name: &'a str,
+            members: usize,
+        }
+
+        let response = Response::builder().with_json(&Room {
+            name: "a\"b",
+            members: 2,
+        });
+        assert_eq!(response.body, br#"{"name":"a\"b","members":2}"#);
+        let bytes = response.into_bytes();
+        let response = String::from_utf8(bytes).unwrap();
+        assert!(response.contains("\r\ncontent-type: application/json; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn test_text_content_types_have_charset() {
+        for (response, content_type) in [
+            (Response::builder().as_html().with_body(""), "text/html"),
+            (Response::builder().as_js().with_body(""), "text/javascript"),
+            (Response::builder().as_css().with_body(""), "text/css"),
+            (Response::builder().as_text().with_body(""), "text/plain"),
+            (
+                Response::builder().as_json().with_body(""),
+                "application/json",
+            ),
+        ] {
+            let response = String::from_utf8(response.into_bytes()).unwrap();
+            let header = format!("\r\ncontent-type: {}; charset=utf-8\r\n", content_type);
+            assert!(response.contains(&header), "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(Status::OK.code(), 200);
+        assert_eq!(Status::NotFound.code(), 404);
+        assert_eq!(Status::HttpVersionNotSupported.code(), 505);
+    }
+
+    #[test]
+    fn test_with_headers_matches_chaining() {
+        let chained = Response::builder()
+            .with_header("cache-control", "no-cache")
+            .with_header("Access-Control-Allow-Origin", "*")
+            .with_body("hi");
+        let bulk = Response::builder()
+            .with_headers([
+                ("Cache-Control", String::from("no-cache")),
+                ("access-control-allow-origin", String::from("*")),
+                ("x-removed", String::from("1")),
+            ])
+            .remove_header("X-Removed")
+            .with_body("hi");
+        assert_eq!(bulk.headers, chained.headers);
+        assert_eq!(bulk.body, chained.body);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let bytes = Response::redirect(Status::Found, "/index.html").into_bytes();
+        let response = String::from_utf8(bytes).unwrap();
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(response.contains("\r\nlocation: /index.html\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_date_and_server_headers() {
+        let bytes = Response::builder().with_body("hi").into_bytes();
+        let response = String::from_utf8(bytes).unwrap();
+        let server = format!("\r\nserver: morse-chat/{}\r\n", env!("CARGO_PKG_VERSION"));
+        assert!(response.contains(&server));
+
+        let date = response
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("date: "))
+            .unwrap();
+        // e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+        let parts = date.split(' ').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 6);
+        assert!(parts[0].len() == 4 && parts[0].ends_with(','));
+        assert!(parts[1].len() == 2 && parts[1].bytes().all(|b| b.is_ascii_digit()));
+        assert!(parts[3].len() == 4 && parts[3].bytes().all(|b| b.is_ascii_digit()));
+        assert_eq!(parts[4].len(), 8);
+        assert_eq!(parts[5], "GMT");
+    }
+
+    #[tokio::test]
+    async fn test_from_error() {
+        let error = crate::request::Request::try_parse_from(&b"BREW /pot HTTP/1.1\r\n\r\n"[..])
+            .await
+            .unwrap_err();
+        let response = String::from_utf8(Response::from_error(&error).into_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\nError 400: expected HTTP method"));
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10 * 1024));
+        let error = crate::request::Request::try_parse_from(long_line.as_bytes())
+            .await
+            .unwrap_err();
+        let response = String::from_utf8(Response::from_error(&error).into_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let error = anyhow::anyhow!("connection to 10.0.0.3 refused");
+        let response = String::from_utf8(Response::from_error(&error).into_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.ends_with("\r\n\r\nError 500: internal server error"));
+        assert!(!response.contains("10.0.0.3"));
+    }
+
+    #[test]
+    fn test_error() {
+        let response = String::from_utf8(
+            Response::error(Status::NotFound, "no such room", false).into_bytes(),
+        )
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\nError 404: no such room"));
+
+        let response =
+            String::from_utf8(Response::error(Status::NotFound, "no such room", true).into_bytes())
+                .unwrap();
+        assert!(response.contains("\r\ncontent-type: application/json"));
+        assert!(response.ends_with(r#"{"status":404,"error":"no such room"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_body_reader() {
+        let body = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let response = Response::builder()
+            .as_css()
+            .with_body_reader(std::io::Cursor::new(body.clone()), body.len() as u64);
+        let mut written = Vec::new();
+        response.try_write_to(&mut written).await.unwrap();
+
+        let head_len = written
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        let head = std::str::from_utf8(&written[..head_len]).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\ncontent-length: 5000\r\n"));
+        assert_eq!(written[head_len..], body);
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_ending_early() {
+        let response = Response::builder().with_body_reader(&b"short"[..], 100);
+        let mut written = Vec::new();
+        assert!(response.try_write_to(&mut written).await.is_err());
+        assert!(written.ends_with(b"\r\n\r\nshort"));
+
+        // a longer reader is cut at the announced length
+        let response = Response::builder().with_body_reader(&b"longer"[..], 4);
+        let mut written = Vec::new();
+        response.try_write_to(&mut written).await.unwrap();
+        assert!(written.ends_with(b"\r\n\r\nlong"));
+    }
+
+    /// Writes `response` through a pipe that takes at most 7 bytes at a
+    /// time, like a socket with a full buffer, and returns what arrived.
+    async fn write_trickling(response: Response) -> Vec<u8> {
+        let (mut writer, mut reader) = tokio::io::duplex(7);
+        let mut written = Vec::new();
+        let (result, _) = tokio::join!(
+            async move { response.try_write_to(&mut writer).await },
+            reader.read_to_end(&mut written),
+        );
+        result.unwrap();
+        written
+    }
+
+    #[tokio::test]
+    async fn test_short_writes_dont_truncate() {
+        let body = "a".repeat(1000);
+        let response = Response::builder().as_text().with_body(body.clone());
+        let written = String::from_utf8(write_trickling(response).await).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with(&format!("\r\n\r\n{}", body)));
+
+        let response =
+            Response::builder().with_body_reader(std::io::Cursor::new(body.clone()), 1000);
+        let written = write_trickling(response).await;
+        assert!(written.ends_with(format!("\r\n\r\n{}", body).as_bytes()));
+    }
+
+    #[test]
+    fn test_existing_date_header_kept() {
+        let bytes = Response::builder()
+            .with_header("Date", "yesterday")
+            .with_body(Vec::new())
+            .into_bytes();
+        let response = String::from_utf8(bytes).unwrap();
+        assert!(response.contains("\r\ndate: yesterday\r\n"));
+        assert_eq!(response.matches("date: ").count(), 1);
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let leap_day = UNIX_EPOCH + std::time::Duration::from_secs(951782400);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
 }
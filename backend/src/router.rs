@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use crate::request::Method;
+
+/// Path parameters captured by a route, keyed by the name of their `:param`
+/// segment.
+pub type Params = HashMap<String, String>;
+
+/// Maps a method and a path pattern to a handler. Patterns consist of static
+/// segments and `:param` segments, e.g. `/api/rooms/:name`, and are matched
+/// against the path without its query string.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+struct Route<H> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+/// A route matching a request.
+#[derive(Debug)]
+pub struct Match<'r, H> {
+    pub handler: &'r H,
+    pub params: Params,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    /// No route matches the path.
+    NotFound,
+    /// Routes match the path, but only for the given methods.
+    MethodNotAllowed(Vec<Method>),
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub fn add(&mut self, method: Method, pattern: &str, handler: H) -> &mut Self {
+        let segments = split_path(pattern)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_owned()),
+                None => Segment::Static(segment.to_owned()),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            segments,
+            handler,
+        });
+        self
+    }
+
+    /// Finds the first route added for `method` whose pattern matches `path`.
+    pub fn route(&self, method: Method, path: &str) -> Result<Match<'_, H>, RouteError> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = route.match_path(path) {
+                if route.method == method {
+                    return Ok(Match {
+                        handler: &route.handler,
+                        params,
+                    });
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method);
+                }
+            }
+        }
+        if allowed.is_empty() {
+            Err(RouteError::NotFound)
+        } else {
+            Err(RouteError::MethodNotAllowed(allowed))
+        }
+    }
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> Route<H> {
+    fn match_path(&self, path: &str) -> Option<Params> {
+        let mut params = Params::new();
+        let mut path_segments = split_path(path);
+        for segment in &self.segments {
+            let path_segment = path_segments.next()?;
+            match segment {
+                Segment::Static(s) if s == path_segment => {}
+                Segment::Static(_) => return None,
+                Segment::Param(_) if path_segment.is_empty() => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), path_segment.to_owned());
+                }
+            }
+        }
+        match path_segments.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        let mut router = Router::new();
+        router
+            .add(Method::Get, "/", "root")
+            .add(Method::Get, "/api/rooms", "list")
+            .add(Method::Get, "/api/rooms/:name", "get")
+            .add(Method::Delete, "/api/rooms/:name", "delete")
+            .add(Method::Get, "/api/rooms/:id", "get by id");
+        router
+    }
+
+    #[test]
+    fn test_static_match() {
+        let router = router();
+        let found = router.route(Method::Get, "/api/rooms").unwrap();
+        assert_eq!(*found.handler, "list");
+        assert!(found.params.is_empty());
+        assert_eq!(*router.route(Method::Get, "/").unwrap().handler, "root");
+        assert_eq!(
+            *router.route(Method::Get, "/?room=a").unwrap().handler,
+            "root"
+        );
+        assert_eq!(
+            router.route(Method::Get, "/api").unwrap_err(),
+            RouteError::NotFound
+        );
+        assert_eq!(
+            router.route(Method::Get, "/api/rooms/a/b").unwrap_err(),
+            RouteError::NotFound
+        );
+    }
+
+    #[test]
+    fn test_param_extraction() {
+        let router = router();
+        let found = router.route(Method::Delete, "/api/rooms/lobby").unwrap();
+        assert_eq!(*found.handler, "delete");
+        assert_eq!(found.params["name"], "lobby");
+        assert_eq!(
+            router.route(Method::Delete, "/api/rooms/").unwrap_err(),
+            RouteError::NotFound
+        );
+    }
+
+    #[test]
+    fn test_method_mismatch() {
+        let router = router();
+        assert_eq!(
+            router.route(Method::Put, "/api/rooms/lobby").unwrap_err(),
+            RouteError::MethodNotAllowed(vec![Method::Get, Method::Delete])
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Builds the acceptor for `wss://` and `https://` connections from a PEM
+/// encoded certificate chain and private key.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let cert = std::fs::read(cert_path)
+        .with_context(|| format!("failed to read certificate {}", cert_path.display()))?;
+    let key = std::fs::read(key_path)
+        .with_context(|| format!("failed to read private key {}", key_path.display()))?;
+    acceptor_from_pem(&cert, &key)
+}
+
+/// Like [load_acceptor], but with the PEM files already read.
+pub fn acceptor_from_pem(cert: &[u8], key: &[u8]) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut &cert[..])
+        .context("invalid certificate")?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found"));
+    }
+    let key = rustls_pemfile::read_all(&mut &key[..])
+        .context("invalid private key")?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key found"))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("certificate and private key don't match")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,79 @@
+//! Counts the allocations needed to send one message to every member of a
+//! room, once with a copy per recipient and once with a shared message.
+//!
+//! Run with `cargo bench --bench broadcast_allocations`.
+
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, DuplexStream};
+use websockets::{Message, WebSocket};
+
+use common::ALLOCATIONS;
+
+const MEMBER_COUNT: usize = 50;
+const MESSAGE_SIZE: usize = 4096;
+/// The message is split into four frames with a four byte header each.
+const FRAME_SIZE: usize = MESSAGE_SIZE + 4 * 4;
+
+fn room() -> (Vec<WebSocket>, Vec<DuplexStream>) {
+    (0..MEMBER_COUNT)
+        .map(|_| {
+            let (client, server) = tokio::io::duplex(2 * FRAME_SIZE);
+            (WebSocket::new(server), client)
+        })
+        .unzip()
+}
+
+async fn receive_all(clients: &mut [DuplexStream], buf: &mut [u8]) {
+    for client in clients {
+        client.read_exact(buf).await.unwrap();
+    }
+}
+
+async fn broadcast_cloned(
+    sockets: &[WebSocket],
+    clients: &mut [DuplexStream],
+    buf: &mut [u8],
+) -> usize {
+    let message = Message::Text("a".repeat(MESSAGE_SIZE));
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for socket in sockets {
+        socket.try_send(message.clone()).await.unwrap();
+    }
+    receive_all(clients, buf).await;
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+async fn broadcast_shared(
+    sockets: &[WebSocket],
+    clients: &mut [DuplexStream],
+    buf: &mut [u8],
+) -> usize {
+    let message = Arc::new(Message::Text("a".repeat(MESSAGE_SIZE)));
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for socket in sockets {
+        socket.send_shared(Arc::clone(&message)).await.unwrap();
+    }
+    receive_all(clients, buf).await;
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (sockets, mut clients) = room();
+        let mut buf = vec![0; FRAME_SIZE];
+        // warm up the channels and stream tasks
+        broadcast_shared(&sockets, &mut clients, &mut buf).await;
+        let cloned = broadcast_cloned(&sockets, &mut clients, &mut buf).await;
+        let shared = broadcast_shared(&sockets, &mut clients, &mut buf).await;
+        println!("sending a {MESSAGE_SIZE} byte message to {MEMBER_COUNT} members");
+        println!("  try_send(message.clone()): {cloned} allocations");
+        println!("  send_shared(Arc::clone()): {shared} allocations");
+    });
+}
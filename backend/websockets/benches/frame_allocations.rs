@@ -0,0 +1,71 @@
+//! Counts the allocations needed to parse a stream of small frames, once with
+//! a fresh payload per frame and once with a reused buffer.
+//!
+//! Run with `cargo bench --bench frame_allocations`.
+
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use bytes::BytesMut;
+use websockets::frame::{self, Frame, OpCode};
+
+use common::ALLOCATIONS;
+
+const FRAME_COUNT: usize = 1000;
+
+async fn encode_frames() -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..FRAME_COUNT {
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_mask([0x37, 0xfa, 0x21, 0x3d])
+            .with_payload(b"hello")
+            .write_to(&mut data)
+            .await
+            .unwrap();
+    }
+    data
+}
+
+async fn parse_owned(mut data: &[u8]) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..FRAME_COUNT {
+        let mut frame = Frame::try_parse_from(&mut data).await.unwrap();
+        if let Some(mask) = frame.mask() {
+            frame::demask(frame.payload_mut(), mask);
+        }
+    }
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+async fn parse_reused(mut data: &[u8]) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut buf = BytesMut::new();
+    for _ in 0..FRAME_COUNT {
+        buf.clear();
+        let header = Frame::parse_header(&mut data).await.unwrap();
+        Frame::parse_body(&mut data, &header, &mut buf)
+            .await
+            .unwrap();
+        if let Some(mask) = header.mask() {
+            frame::demask(&mut buf, mask);
+        }
+    }
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let data = encode_frames().await;
+        let owned = parse_owned(&data).await;
+        let reused = parse_reused(&data).await;
+        println!("parsing {FRAME_COUNT} frames");
+        println!("  try_parse_from:            {owned} allocations");
+        println!("  parse_header + parse_body: {reused} allocations");
+    });
+}
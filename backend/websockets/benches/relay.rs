@@ -0,0 +1,113 @@
+//! Relays a large binary message from one socket to the other members of a
+//! room and reports the time and allocations per relayed message.
+//!
+//! Run with `cargo bench --bench relay`.
+
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use websockets::frame::{Frame, OpCode};
+use websockets::WebSocket;
+
+use common::ALLOCATIONS;
+
+const MEMBER_COUNT: usize = 50;
+const MESSAGE_SIZE: usize = 256 * 1024;
+/// Size of the fragments the sender uses.
+const FRAGMENT_SIZE: usize = 16 * 1024;
+/// Outgoing messages are split into 1 KiB frames with a four byte header.
+const RELAYED_SIZE: usize = MESSAGE_SIZE / 1024 * (1024 + 4);
+const ROUNDS: usize = 20;
+
+async fn encode_message() -> Vec<u8> {
+    let payload = vec![0x2a; MESSAGE_SIZE];
+    let mut data = Vec::new();
+    for (idx, chunk) in payload.chunks(FRAGMENT_SIZE).enumerate() {
+        let mut builder = Frame::builder();
+        if idx == 0 {
+            builder.with_opcode(OpCode::Binary);
+        } else {
+            builder.with_opcode(OpCode::Continuation);
+        }
+        if (idx + 1) * FRAGMENT_SIZE < MESSAGE_SIZE {
+            builder.is_not_final();
+        } else {
+            builder.is_final();
+        }
+        builder.write_payload_to(chunk, &mut data).await.unwrap();
+    }
+    data
+}
+
+async fn relay(
+    encoded: &[u8],
+    sender: &WebSocket,
+    sender_client: &mut DuplexStream,
+    peers: &[WebSocket],
+    peer_clients: &mut [DuplexStream],
+    buf: &mut [u8],
+) {
+    sender_client.write_all(encoded).await.unwrap();
+    let message = loop {
+        match sender.poll_next_message().await {
+            Some(message) => break Arc::new(message.unwrap()),
+            None => tokio::task::yield_now().await,
+        }
+    };
+    for peer in peers {
+        peer.send_shared(Arc::clone(&message)).await.unwrap();
+    }
+    for client in peer_clients {
+        client.read_exact(buf).await.unwrap();
+    }
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let encoded = encode_message().await;
+        let (mut sender_client, sender) = tokio::io::duplex(2 * MESSAGE_SIZE);
+        let sender = WebSocket::new(sender);
+        let (peers, mut peer_clients): (Vec<_>, Vec<_>) = (0..MEMBER_COUNT)
+            .map(|_| {
+                let (client, server) = tokio::io::duplex(2 * RELAYED_SIZE);
+                (WebSocket::new(server), client)
+            })
+            .unzip();
+        let mut buf = vec![0; RELAYED_SIZE];
+
+        // warm up the channels and stream tasks
+        relay(
+            &encoded,
+            &sender,
+            &mut sender_client,
+            &peers,
+            &mut peer_clients,
+            &mut buf,
+        )
+        .await;
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            relay(
+                &encoded,
+                &sender,
+                &mut sender_client,
+                &peers,
+                &mut peer_clients,
+                &mut buf,
+            )
+            .await;
+        }
+        let elapsed = start.elapsed() / ROUNDS as u32;
+        let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - before) / ROUNDS;
+        println!("relaying a {MESSAGE_SIZE} byte message to {MEMBER_COUNT} members");
+        println!("  {elapsed:?} and {allocations} allocations per message");
+    });
+}
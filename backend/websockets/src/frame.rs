@@ -1,11 +1,26 @@
+use std::io::IoSlice;
+
+use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// Control frames carry at most 125 bytes, so they never use the extended
+/// payload length forms.
+const MAX_CONTROL_PAYLOAD_LEN: u8 = 125;
+/// Payloads are read in chunks of at most this size, so a frame announcing a
+/// huge payload only takes as much memory as the peer actually sends.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+/// Two bytes, eight bytes of extended payload length and the masking key.
+const MAX_HEADER_LEN: usize = 14;
+/// Returned by [Frame::parse_header] if the reader ends before a new frame
+/// starts, i.e. the peer closed the connection between frames.
+pub const CONNECTION_CLOSED: &str = "connection closed";
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Frame {
     is_final: bool,
     opcode: OpCode,
     mask: Option<[u8; 4]>,
-    payload: Vec<u8>,
+    payload: BytesMut,
 }
 
 impl Frame {
@@ -29,86 +44,150 @@ impl Frame {
         &mut self.payload
     }
 
+    /// Returns the payload as a buffer that can be shared without copying.
+    pub fn into_payload(self) -> Bytes {
+        self.payload.freeze()
+    }
+
     pub fn mask(&self) -> Option<[u8; 4]> {
         self.mask
     }
 
-    pub async fn try_parse_from<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Self, &'static str> {
+    pub async fn try_parse_from<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+    ) -> Result<Self, &'static str> {
+        let header = Self::parse_header(reader).await?;
+        let mut payload = BytesMut::new();
+        Self::parse_body(reader, &header, &mut payload).await?;
+
+        let Header {
+            is_final,
+            opcode,
+            mask,
+            ..
+        } = header;
+        let frame = Frame {
+            is_final,
+            opcode,
+            mask,
+            payload,
+        };
+        Ok(frame)
+    }
+
+    /// Reads a frame up to, but excluding, its payload.
+    pub async fn parse_header<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+    ) -> Result<Header, &'static str> {
         let mut first_two = [0; 2];
-        reader.read(&mut first_two).await.map_err(|_| "error reading first two header bytes")?;
-        
+        // ending before the first byte is a closed connection, not a frame
+        if reader
+            .read(&mut first_two[..1])
+            .await
+            .map_err(|_| "error reading first two header bytes")?
+            == 0
+        {
+            return Err(CONNECTION_CLOSED);
+        }
+        reader
+            .read_exact(&mut first_two[1..])
+            .await
+            .map_err(|_| "error reading first two header bytes")?;
+
         let is_final = first_two[0] >> 7 != 0;
+        let rsv = (first_two[0] >> 4) & 0x07;
         let opcode = OpCode::try_from(first_two[0] & 0x0f)?;
         let is_masked = first_two[1] >> 7 != 0;
+        if opcode.is_control() && first_two[1] & 0x7f > MAX_CONTROL_PAYLOAD_LEN {
+            return Err("control frame payload too long");
+        }
         let payload_len = match first_two[1] & 0x7f {
             126 => {
                 let mut next = [0; 2];
-                reader.read(&mut next).await.map_err(|_| "error reading payload length")?;
+                reader
+                    .read_exact(&mut next)
+                    .await
+                    .map_err(|_| "error reading payload length")?;
                 u16::from_be_bytes(next) as u64
-            },
+            }
             127 => {
                 let mut next = [0; 8];
-                reader.read(&mut next).await.map_err(|_| "error reading payload length")?;
+                reader
+                    .read_exact(&mut next)
+                    .await
+                    .map_err(|_| "error reading payload length")?;
+                if next[0] >> 7 != 0 {
+                    return Err("most significant bit of payload length set");
+                }
                 u64::from_be_bytes(next)
-            },
+            }
             len => len as u64,
         };
+        if usize::try_from(payload_len).is_err() {
+            return Err("payload length too large");
+        }
 
         let mask = if is_masked {
             let mut next = [0; 4];
-            reader.read(&mut next).await.map_err(|_| "error reading masking key")?;
+            reader
+                .read_exact(&mut next)
+                .await
+                .map_err(|_| "error reading masking key")?;
             Some(next)
         } else {
             None
         };
 
-        let mut payload = vec![0; payload_len as usize];
-        reader.read(&mut payload[..]).await.map_err(|_| "error reading payload")?;
-
-        let frame = Frame { is_final, opcode, mask, payload };
-        Ok(frame)
+        Ok(Header {
+            is_final,
+            rsv,
+            opcode,
+            mask,
+            payload_len,
+        })
     }
 
-    pub async fn write_to<W: AsyncWriteExt + Unpin>(self, dest: &mut W) -> Result<(), &'static str> {
-        let opcode: u8 = self.opcode.into();
-        let is_final = if self.is_final { 0x80 } else { 0x0 };
-        let first = is_final | opcode;
-
-        dest.write(&[first]).await.map_err(|_| "error writing first byte")?;
-        
-        let is_masked = if self.mask.is_some() { 0x80 } else { 0x0 };
-        let actual_len = self.payload.len();
-        let write_len_result = if actual_len < 126 {
-            let bytes = [actual_len as u8 | is_masked];
-            dest.write(&bytes).await
-        } else if 126 <= actual_len && actual_len <= 0x7fff {
-            let [a, b] = (actual_len as u16).to_be_bytes();
-            dest.write(&[126 | is_masked, a, b]).await
-        } else {
-            let [a, b, c, d, e, f, g, h] = (actual_len as u64).to_be_bytes();
-            dest.write(&[127 | is_masked, a, b, c, d, e, f, g, h]).await
-        };
-
-        write_len_result.map_err(|_| "error writing payload length")?;
-
-        if let Some(mask) = self.mask {
-            dest.write(&mask).await.map_err(|_| "error writing mask")?;
+    /// Reads the payload of the frame described by `header` and appends it to
+    /// `buf`, so one buffer can be reused across frames. The payload is not
+    /// demasked.
+    pub async fn parse_body<R: AsyncReadExt + Unpin>(
+        reader: &mut R,
+        header: &Header,
+        buf: &mut BytesMut,
+    ) -> Result<(), &'static str> {
+        let mut remaining =
+            usize::try_from(header.payload_len).map_err(|_| "payload length too large")?;
+        let start = buf.len();
+        while remaining > 0 {
+            let chunk_start = buf.len();
+            let chunk_len = remaining.min(READ_CHUNK_SIZE);
+            buf.resize(chunk_start + chunk_len, 0);
+            if reader.read_exact(&mut buf[chunk_start..]).await.is_err() {
+                buf.truncate(start);
+                return Err("error reading payload");
+            }
+            remaining -= chunk_len;
         }
-
-        dest.write(&self.payload.as_slice())
-            .await
-            .map_err(|_| "error writing payload")?;
-
         Ok(())
     }
+
+    pub async fn write_to<W: AsyncWriteExt + Unpin>(
+        self,
+        dest: &mut W,
+    ) -> Result<(), &'static str> {
+        let builder = Builder {
+            is_final: self.is_final,
+            opcode: self.opcode,
+            mask: self.mask,
+        };
+        builder.write_payload_to(&self.payload, dest).await
+    }
 }
 
 pub fn demask(data: &mut [u8], mask: [u8; 4]) {
-    data.into_iter()
+    data.iter_mut()
         .zip(mask.into_iter().cycle())
-        .for_each(|(dr, m)| {
-            *dr = *dr ^ m
-        });
+        .for_each(|(dr, m)| *dr ^= m);
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -125,7 +204,10 @@ pub enum OpCode {
 
 impl OpCode {
     pub fn is_control(&self) -> bool {
-        matches!(self, Self::Close | Self::Ping | Self::Pong | Self::ControlReserved(_))
+        matches!(
+            self,
+            Self::Close | Self::Ping | Self::Pong | Self::ControlReserved(_)
+        )
     }
 
     pub fn is_non_control(&self) -> bool {
@@ -133,6 +215,23 @@ impl OpCode {
     }
 }
 
+/// Lowercase name of the opcode for logs, e.g. `text` or `reserved 0x3`.
+impl std::fmt::Display for OpCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Continuation => f.write_str("continuation"),
+            Self::Text => f.write_str("text"),
+            Self::Binary => f.write_str("binary"),
+            Self::Close => f.write_str("close"),
+            Self::Ping => f.write_str("ping"),
+            Self::Pong => f.write_str("pong"),
+            Self::NonControlReserved(c) | Self::ControlReserved(c) => {
+                write!(f, "reserved {:#x}", c)
+            }
+        }
+    }
+}
+
 impl TryFrom<u8> for OpCode {
     type Error = &'static str;
 
@@ -144,28 +243,86 @@ impl TryFrom<u8> for OpCode {
             0x8 => Ok(Self::Close),
             0x9 => Ok(Self::Ping),
             0xa => Ok(Self::Pong),
-            other if 3 <= other && other <= 7 => Ok(Self::NonControlReserved(other)),
-            other if 0xb <= other && other <= 0xf => Ok(Self::ControlReserved(other)),
+            other if (3..=7).contains(&other) => Ok(Self::NonControlReserved(other)),
+            other if (0xb..=0xf).contains(&other) => Ok(Self::ControlReserved(other)),
             _ => Err("unrecognized opcode"),
         }
     }
 }
 
-impl Into<u8> for OpCode {
-    fn into(self) -> u8 {
-        match self {
-            Self::Continuation => 0x0,
-            Self::Text => 0x1,
-            Self::Binary => 0x2,
-            Self::NonControlReserved(c) => c,
-            Self::Close => 0x8,
-            Self::Ping => 0x9,
-            Self::Pong => 0xa,
-            Self::ControlReserved(c) => c,
+impl From<OpCode> for u8 {
+    fn from(code: OpCode) -> u8 {
+        match code {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::NonControlReserved(c) => c,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xa,
+            OpCode::ControlReserved(c) => c,
+        }
+    }
+}
+
+/// Status code of a Close frame. Only codes a peer may actually send are
+/// valid, i.e. the registered codes from 1000 to 1014 except 1004, 1005 and
+/// 1006, and the codes from 3000 to 4999 for libraries and applications.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CloseCode(u16);
+
+impl TryFrom<u16> for CloseCode {
+    type Error = &'static str;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            1004..=1006 => Err("reserved close code"),
+            1000..=1014 | 3000..=4999 => Ok(Self(code)),
+            _ => Err("invalid close code"),
         }
     }
 }
 
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        code.0
+    }
+}
+
+/// Everything in a frame except its payload, see [Frame::parse_header].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    is_final: bool,
+    rsv: u8,
+    opcode: OpCode,
+    mask: Option<[u8; 4]>,
+    payload_len: u64,
+}
+
+impl Header {
+    pub fn is_final(&self) -> bool {
+        self.is_final
+    }
+
+    /// Returns the three reserved bits, RSV1 being the most significant. They
+    /// are only set by negotiated extensions.
+    pub fn rsv(&self) -> u8 {
+        self.rsv
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn mask(&self) -> Option<[u8; 4]> {
+        self.mask
+    }
+
+    pub fn payload_len(&self) -> u64 {
+        self.payload_len
+    }
+}
+
 pub struct Builder {
     is_final: bool,
     opcode: OpCode,
@@ -193,14 +350,94 @@ impl Builder {
         self
     }
 
-    pub fn with_payload(&mut self, payload: Vec<u8>) -> Frame {
+    pub fn with_payload<P: AsRef<[u8]>>(&mut self, payload: P) -> Frame {
         Frame {
             is_final: self.is_final,
             opcode: self.opcode,
             mask: self.mask,
+            payload: BytesMut::from(payload.as_ref()),
+        }
+    }
+
+    /// Builds a final Close frame with the status `code` and `reason`. As
+    /// control frames carry at most 125 bytes, the reason is cut to 123 bytes,
+    /// at a character boundary.
+    pub fn with_close(&mut self, code: u16, reason: &str) -> Frame {
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut payload = BytesMut::with_capacity(2 + len);
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(&reason.as_bytes()[..len]);
+        Frame {
+            is_final: true,
+            opcode: OpCode::Close,
+            mask: self.mask,
             payload,
         }
     }
+
+    /// Writes a frame with a borrowed payload, so a payload that is sent to
+    /// several streams doesn't have to be copied for each of them. Header and
+    /// payload go out in one vectored write if the stream supports it.
+    pub async fn write_payload_to<W: AsyncWriteExt + Unpin>(
+        &self,
+        payload: &[u8],
+        dest: &mut W,
+    ) -> Result<(), &'static str> {
+        let (header, header_len) = self.header_for(payload.len());
+        write_all_vectored(dest, &header[..header_len], payload)
+            .await
+            .map_err(|_| "error writing frame")
+    }
+
+    /// Returns the header of a frame with `payload_len` bytes of payload and
+    /// how many bytes of the buffer it takes.
+    fn header_for(&self, payload_len: usize) -> ([u8; MAX_HEADER_LEN], usize) {
+        let mut buf = [0; MAX_HEADER_LEN];
+        let opcode: u8 = self.opcode.into();
+        let is_final = if self.is_final { 0x80 } else { 0x0 };
+        buf[0] = is_final | opcode;
+
+        let is_masked = if self.mask.is_some() { 0x80 } else { 0x0 };
+        let mut len = 2;
+        if payload_len < 126 {
+            buf[1] = payload_len as u8 | is_masked;
+        } else if payload_len <= u16::MAX as usize {
+            buf[1] = 126 | is_masked;
+            buf[2..4].copy_from_slice(&(payload_len as u16).to_be_bytes());
+            len = 4;
+        } else {
+            buf[1] = 127 | is_masked;
+            buf[2..10].copy_from_slice(&(payload_len as u64).to_be_bytes());
+            len = 10;
+        }
+
+        if let Some(mask) = self.mask {
+            buf[len..len + 4].copy_from_slice(&mask);
+            len += 4;
+        }
+        (buf, len)
+    }
+}
+
+/// Writes `header` followed by `payload` like two `write_all` calls, but hands
+/// both to the stream at once while the header isn't completely written.
+async fn write_all_vectored<W: AsyncWriteExt + Unpin>(
+    dest: &mut W,
+    header: &[u8],
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < header.len() {
+        let bufs = [IoSlice::new(&header[written..]), IoSlice::new(payload)];
+        match dest.write_vectored(&bufs).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    dest.write_all(&payload[written - header.len()..]).await
 }
 
 impl Default for Builder {
@@ -215,8 +452,90 @@ impl Default for Builder {
 
 #[cfg(test)]
 mod tests {
-    use std::error::Error;
     use super::*;
+    use crate::tests::TestWriter;
+    use std::error::Error;
+
+    #[tokio::test]
+    async fn test_control_frame_with_extended_length_rejected() {
+        let data = [0x89, 0x7e, 0x00, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert!(Frame::try_parse_from(&mut &data[..]).await.is_err());
+        let data = [0x88, 0x7f, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x03, 0xe8];
+        assert!(Frame::try_parse_from(&mut &data[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payload_length_with_msb_rejected() {
+        let data = [0x82, 0xff, 0x80, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0, 0];
+        assert!(Frame::try_parse_from(&mut &data[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_huge_announced_payload_fails_without_allocating() {
+        // 16 TiB announced, only a few bytes sent
+        let data = [0x82, 0x7f, 0, 0, 0x10, 0, 0, 0, 0, 0, 1, 2, 3];
+        let mut buf = BytesMut::new();
+        let header = Frame::parse_header(&mut &data[..10]).await.unwrap();
+        assert!(Frame::parse_body(&mut &data[10..], &header, &mut buf)
+            .await
+            .is_err());
+        assert!(buf.is_empty());
+        assert!(buf.capacity() <= READ_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frames_rejected() {
+        let data = [0x81, 0xfe, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04, 0x61, 0x62];
+        assert!(Frame::try_parse_from(&mut &data[..]).await.is_ok());
+        for len in 0..data.len() {
+            assert!(
+                Frame::try_parse_from(&mut &data[..len]).await.is_err(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_reserved_bits() {
+        for (first, rsv) in [
+            (0x81, 0b000),
+            (0xc1, 0b100),
+            (0xa1, 0b010),
+            (0x91, 0b001),
+            (0xf1, 0b111),
+        ] {
+            let data = [first, 0x00];
+            let header = Frame::parse_header(&mut &data[..]).await.unwrap();
+            assert_eq!(header.rsv(), rsv);
+            assert_eq!(header.opcode(), OpCode::Text);
+            assert!(header.is_final());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_of_stream_before_header() {
+        assert_eq!(
+            Frame::try_parse_from(&mut &[][..]).await,
+            Err(CONNECTION_CLOSED)
+        );
+        // a frame that started is cut off, not closed
+        let result = Frame::try_parse_from(&mut &[0x81][..]).await;
+        assert!(matches!(result, Err(error) if error != CONNECTION_CLOSED));
+    }
+
+    #[tokio::test]
+    async fn test_parse_arbitrary_headers() {
+        // every combination of the first two bytes, followed by too little
+        // data for most of them, has to fail or succeed without panicking
+        for first in 0..=u8::MAX {
+            for second in 0..=u8::MAX {
+                let mut data = vec![first, second];
+                data.extend_from_slice(&[0xff; 12]);
+                let _ = Frame::try_parse_from(&mut &data[..]).await;
+            }
+        }
+    }
 
     #[tokio::test]
     async fn test_parse_unmasked_text() -> Result<(), Box<dyn Error>> {
@@ -239,7 +558,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_parse_masked_text() -> Result<(), Box<dyn Error>> {
-        let data = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let data = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
         let frame = Frame::try_parse_from(&mut &data[..]).await?;
         assert!(frame.is_final());
         assert_eq!(frame.opcode(), OpCode::Text);
@@ -256,31 +577,130 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_parse_body_appends() -> Result<(), Box<dyn Error>> {
+        let data = [0x01, 0x02, 0x48, 0x65, 0x80, 0x03, 0x6c, 0x6c, 0x6f];
+        let mut reader = &data[..];
+        let mut buf = BytesMut::new();
+        let header = Frame::parse_header(&mut reader).await?;
+        assert_eq!(header.payload_len(), 2);
+        Frame::parse_body(&mut reader, &header, &mut buf).await?;
+        let header = Frame::parse_header(&mut reader).await?;
+        assert!(header.is_final());
+        assert_eq!(header.opcode(), OpCode::Continuation);
+        Frame::parse_body(&mut reader, &header, &mut buf).await?;
+        assert_eq!(&buf[..], "Hello".as_bytes());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_write_unmasked() -> Result<(), Box<dyn Error>> {
         let data = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
         let frame = Frame::builder()
             .is_final()
             .with_opcode(OpCode::Text)
-            .with_payload(vec![0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+            .with_payload([0x48, 0x65, 0x6c, 0x6c, 0x6f]);
         let mut buffer = Vec::with_capacity(data.len());
         frame.write_to(&mut buffer).await?;
         assert_eq!(&buffer, &data);
         Ok(())
     }
 
+    #[test]
+    fn test_with_close() {
+        let frame = Frame::builder().is_not_final().with_close(1001, "bye");
+        assert!(frame.is_final());
+        assert_eq!(frame.opcode(), OpCode::Close);
+        assert_eq!(frame.payload(), &[0x03, 0xe9, b'b', b'y', b'e']);
+
+        let reason = format!("{}ä", "a".repeat(122));
+        let frame = Frame::builder().with_close(1000, &reason);
+        assert_eq!(frame.payload().len(), 124);
+        assert_eq!(&frame.payload()[2..], "a".repeat(122).as_bytes());
+    }
+
+    #[test]
+    fn test_close_code() {
+        assert_eq!(CloseCode::try_from(1000).map(u16::from), Ok(1000));
+        assert_eq!(CloseCode::try_from(4000).map(u16::from), Ok(4000));
+        for invalid in [0, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            assert!(CloseCode::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_opcode_display() {
+        assert_eq!(OpCode::Continuation.to_string(), "continuation");
+        assert_eq!(OpCode::Text.to_string(), "text");
+        assert_eq!(OpCode::Pong.to_string(), "pong");
+        assert_eq!(OpCode::NonControlReserved(0x3).to_string(), "reserved 0x3");
+        assert_eq!(OpCode::ControlReserved(0xb).to_string(), "reserved 0xb");
+    }
+
     #[tokio::test]
     async fn test_write_masked() -> Result<(), Box<dyn Error>> {
-        let data = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let data = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
         let frame = Frame::builder()
             .is_final()
             .with_opcode(OpCode::Text)
             .with_mask([0x37, 0xfa, 0x21, 0x3d])
-            .with_payload(vec![0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+            .with_payload([0x7f, 0x9f, 0x4d, 0x51, 0x58]);
         let mut buffer = Vec::with_capacity(data.len());
         frame.write_to(&mut buffer).await?;
         assert_eq!(&buffer, &data);
         Ok(())
     }
-}
 
+    #[tokio::test]
+    async fn test_frame_written_at_once() {
+        let payload = vec![7; 300];
+        let mut writer = TestWriter::default();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Binary)
+            .write_payload_to(&payload, &mut writer)
+            .await
+            .unwrap();
+        assert_eq!(writer.writes.len(), 1);
+        let written = &writer.writes[0];
+        assert_eq!(&written[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(&written[4..], &payload[..]);
+    }
+
+    #[tokio::test]
+    async fn test_short_writes_dont_truncate() {
+        let payload = vec![7; 300];
+        let mut writer = TestWriter {
+            max_write: 3,
+            ..TestWriter::default()
+        };
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Binary)
+            .write_payload_to(&payload, &mut writer)
+            .await
+            .unwrap();
+        let written = writer.written();
+        let frame = Frame::try_parse_from(&mut &written[..]).await.unwrap();
+        assert_eq!(frame.payload(), &payload[..]);
+    }
+
+    #[tokio::test]
+    async fn test_payload_length_forms() {
+        // the shortest form that fits has to be used
+        for (len, header_len) in [(125, 2), (126, 4), (0x8000, 4), (0xffff, 4), (0x10000, 10)] {
+            let payload = vec![3; len];
+            let mut written = Vec::new();
+            Frame::builder()
+                .with_opcode(OpCode::Binary)
+                .write_payload_to(&payload, &mut written)
+                .await
+                .unwrap();
+            assert_eq!(written.len(), header_len + len, "{}", len);
+            let frame = Frame::try_parse_from(&mut &written[..]).await.unwrap();
+            assert_eq!(frame.payload(), &payload[..]);
+        }
+    }
+}
@@ -1,32 +1,127 @@
 use std::{
     collections::VecDeque,
+    net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 
-use frame::{Frame, OpCode};
+use bytes::{Bytes, BytesMut};
+use frame::{CloseCode, Frame, OpCode};
 use futures::Future;
 use pin_project::pin_project;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc::{self, Sender};
 use tokio::{
-    net::TcpStream,
-    sync::Mutex,
+    sync::{Mutex, Notify},
     task::{self, JoinHandle},
+    time::Instant,
 };
+use tracing::{debug, Instrument, Span};
 
-mod frame;
+pub mod frame;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+const MAX_CONTROL_PAYLOAD_SIZE: usize = 125;
+/// Appended to the key of an opening handshake, see [accept_key].
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Returns the `Sec-WebSocket-Accept` value that answers the
+/// `Sec-WebSocket-Key` `key` (RFC 6455, section 4.2.2). A server sends it in
+/// its handshake response and a client checks that it got it back.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
 
 #[derive(Debug)]
 pub struct WebSocket {
-    stream_task: JoinHandle<()>,
+    reader: WebSocketReader,
+    writer: WebSocketWriter,
+    protocol: Option<String>,
+    extensions: Vec<String>,
+    peer_addr: Option<SocketAddr>,
+}
+
+/// The receiving half of a [WebSocket], see [WebSocket::split].
+#[derive(Debug)]
+pub struct WebSocketReader {
     recv_queue: Arc<Mutex<VecDeque<Result<Message, MessageError>>>>,
+    /// Notified whenever the stream task queued a message or ended.
+    received: Arc<Notify>,
+    /// Notified whenever a message was taken from the queue, see
+    /// [Config::max_queued_messages].
+    drained: Arc<Notify>,
+    is_closed: Arc<AtomicBool>,
+    stats: Arc<StatsCounters>,
+}
+
+/// The sending half of a [WebSocket], see [WebSocket::split].
+#[derive(Debug)]
+pub struct WebSocketWriter {
+    stream_task: JoinHandle<()>,
     cmd_channel: Sender<Cmd>,
+    stats: Arc<StatsCounters>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum size in bytes of a reassembled message. Larger messages close
+    /// the connection with status code 1009.
+    pub max_message_size: usize,
+    /// Maximum number of frames a message may be fragmented into. Messages
+    /// with more fragments close the connection with status code 1009, so
+    /// floods of tiny fragments don't pass the size limit.
+    pub max_fragments: usize,
+    /// Number of received messages that may wait to be taken with
+    /// [WebSocket::next_message]. While that many are queued, the stream task
+    /// reads nothing from the peer, which slows the peer down instead of
+    /// buffering without limit.
+    pub max_queued_messages: usize,
+    /// Subprotocol negotiated during the handshake, if any.
+    pub protocol: Option<String>,
+    /// Extensions negotiated during the handshake. Without any, frames with
+    /// reserved bits set are rejected. With some, the reserved bits are left
+    /// to them and not checked.
+    pub extensions: Vec<String>,
+    /// Address of the peer, which is included in the stream task's logs.
+    pub peer_addr: Option<SocketAddr>,
+    /// Span the stream task runs in, e.g. to tag its logs with the room the
+    /// connection belongs to. Defaults to a `websocket` span with
+    /// [Config::peer_addr].
+    pub span: Option<Span>,
+    /// Notified whenever the stream task queued a message or ended, so a
+    /// single task can wait for any of several sockets to have something.
+    pub received_notify: Option<Arc<Notify>>,
+    /// Whether received pings and pongs are queued as [Message::Ping] and
+    /// [Message::Pong]. Pings are answered either way.
+    pub deliver_control_messages: bool,
+    /// Time the peer has to complete a message once its first byte arrived.
+    /// Waiting for a new message is not limited.
+    pub read_timeout: Option<Duration>,
+    /// Time writing a single message may take, e.g. while the peer doesn't
+    /// read and the socket buffer is full.
+    pub write_timeout: Option<Duration>,
+    /// Time after which a peer that hasn't sent a single frame is closed
+    /// with status code 1001. Sending to the peer doesn't count as activity.
+    pub idle_timeout: Option<Duration>,
 }
 
 enum Cmd {
-    Close,
-    Send(Message),
+    /// Closes the connection, with a status code and reason if given.
+    Close(Option<(u16, String)>),
+    Send(Arc<Message>),
 }
 
 enum NextStep {
@@ -37,22 +132,95 @@ enum NextStep {
 #[derive(Debug, Clone)]
 pub enum Message {
     Text(String),
-    Binary(Vec<u8>),
+    Binary(Bytes),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
 pub enum MessageError {
-    ConnectionClosed,
+    /// The peer closed the connection, with the status code it sent if any.
+    ConnectionClosed(Option<u16>),
     InvalidMessage,
     Network,
+    /// The stream task has ended, so no more messages can be sent.
+    SendFailed,
+    TooBig,
+    /// Nothing was received for longer than [Config::idle_timeout].
+    Idle,
+}
+
+impl MessageError {
+    /// Status code of the Close frame that ends a connection failing with
+    /// this error. A closing peer gets its own code echoed, or 1000 if it
+    /// didn't send one.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            Self::ConnectionClosed(code) => code.unwrap_or(CLOSE_NORMAL),
+            Self::InvalidMessage => CLOSE_PROTOCOL_ERROR,
+            Self::Network | Self::SendFailed => CLOSE_INTERNAL_ERROR,
+            Self::TooBig => CLOSE_MESSAGE_TOO_BIG,
+            Self::Idle => CLOSE_GOING_AWAY,
+        }
+    }
+}
+
+/// Returned by [WebSocket::try_send_now] with the message that wasn't sent.
+#[derive(Debug, Clone)]
+pub enum TrySendError {
+    /// The queue of messages waiting to be written is full, e.g. because the
+    /// peer doesn't read.
+    Full(Arc<Message>),
+    /// The stream task has ended, so no more messages can be sent.
+    Closed(Arc<Message>),
+}
+
+/// Returned by [WebSocket::send_text_bytes] if the text wasn't sent.
+#[derive(Debug, Clone)]
+pub enum SendTextError {
+    /// The bytes aren't valid UTF-8, so they weren't queued at all.
+    InvalidUtf8(std::string::FromUtf8Error),
+    /// The stream task has ended, so no more messages can be sent.
+    Closed(Message),
+}
+
+/// Snapshot of a connection's counters, see [WebSocket::stats]. Bytes are
+/// counted as message payload, without frame headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Time the [WebSocket] was created.
+    pub connected_at: Instant,
+    /// Number of messages completely written to the peer.
+    pub messages_sent: u64,
+    /// Number of messages read from the peer.
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Counters the stream task updates, shared with both halves of the socket.
+#[derive(Debug)]
+struct StatsCounters {
+    connected_at: Instant,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
 }
 
+/// Resolves to whichever comes first: data becoming readable or a command.
+///
+/// Readiness is detected by filling the stream's read buffer, so the bytes
+/// seen here are the same ones the frame parser consumes afterwards. If both
+/// are ready, `read_first` decides which one wins. The stream task alternates
+/// it, so a flood of incoming frames can't starve a queued [Cmd::Close].
 #[pin_project]
 struct NextStepFuture<S, C> {
     #[pin]
     stream: S,
     #[pin]
     channel: C,
+    read_first: bool,
 }
 
 impl WebSocket {
@@ -62,138 +230,644 @@ impl WebSocket {
     ///
     /// For sending messages, use [WebSocket::try_send]. For getting a newly
     /// received message from the queue, use [WebSocket::next_message_if_exists].
-    /// To close the websocket and with it the `TcpStream`, use [WebSocket::shutdown].
-    pub fn new(stream: TcpStream) -> Self {
+    /// To close the websocket and with it the stream, use [WebSocket::shutdown].
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_config(stream, Config::default())
+    }
+
+    /// Like [WebSocket::new], but with a custom [Config].
+    pub fn with_config<S>(stream: S, config: Config) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let protocol = config.protocol.clone();
+        let extensions = config.extensions.clone();
+        let peer_addr = config.peer_addr;
+        let span = config
+            .span
+            .clone()
+            .unwrap_or_else(|| tracing::debug_span!("websocket", ?peer_addr));
         let (cmd_channel, mut rx) = mpsc::channel(Self::CMD_CHANNEL_BUF_SIZE);
         let queue = Arc::new(Mutex::new(VecDeque::new()));
         let queue_clone = Arc::clone(&queue);
-        let stream_task = task::spawn(async move {
-            let mut stream = stream;
+        let is_closed = Arc::new(AtomicBool::new(false));
+        let is_closed_clone = Arc::clone(&is_closed);
+        let received = Arc::new(Notify::new());
+        let received_clone = Arc::clone(&received);
+        let drained = Arc::new(Notify::new());
+        let drained_clone = Arc::clone(&drained);
+        let stats = Arc::new(StatsCounters::new());
+        let stats_clone = Arc::clone(&stats);
+        let stream_loop = async move {
+            // writes pass through the reader unbuffered
+            let mut stream = BufReader::new(stream);
+            let mut read_first = true;
+            let mut last_activity = Instant::now();
+            // command taken from the channel while a fragmented message was
+            // written, which has to wait for it to be complete. Taking no more
+            // than one keeps the channel's backpressure on the senders.
+            let mut held_back = None;
+            let notify_received = || {
+                received_clone.notify_one();
+                if let Some(notify) = &config.received_notify {
+                    notify.notify_one();
+                }
+            };
             loop {
-                let next_step = NextStepFuture::new(stream.peek(&mut [0]), rx.recv()).await;
+                let next_step = if let Some(cmd) = held_back.take() {
+                    NextStep::Write(cmd)
+                } else {
+                    let readable = async {
+                        // a message taken meanwhile leaves a permit behind, so
+                        // it can't be missed
+                        while queue_clone.lock().await.len() >= config.max_queued_messages {
+                            drained_clone.notified().await;
+                        }
+                        stream.fill_buf().await.map(|_| ())
+                    };
+                    let next_step = NextStepFuture::new(readable, rx.recv(), read_first);
+                    let idle_left = config.idle_timeout.map(|limit| {
+                        (last_activity + limit).saturating_duration_since(Instant::now())
+                    });
+                    if let Some(next_step) = with_timeout(idle_left, next_step).await {
+                        next_step
+                    } else {
+                        debug!("closing idle connection.");
+                        let close = Frame::builder()
+                            .with_close(MessageError::Idle.close_code(), "idle")
+                            .write_to(&mut stream);
+                        let _ = with_timeout(config.write_timeout, close).await;
+                        queue_clone.lock().await.push_back(Err(MessageError::Idle));
+                        notify_received();
+                        break;
+                    }
+                };
+                read_first = !read_first;
                 match next_step {
                     NextStep::Read => {
-                        let msg = read_message_from(&mut stream).await;
+                        last_activity = Instant::now();
+                        let read = read_message_from(&mut stream, &config);
+                        let msg = with_timeout(config.read_timeout, read)
+                            .await
+                            .unwrap_or_else(|| {
+                                debug!("read timed out.");
+                                Err(MessageError::Network)
+                            });
+                        let msg = match msg {
+                            Ok(Some(msg)) => Ok(msg),
+                            Ok(None) => continue,
+                            Err(error) => Err(error),
+                        };
+                        if let Err(error @ MessageError::Network) = &msg {
+                            let close = Frame::builder()
+                                .with_close(error.close_code(), "")
+                                .write_to(&mut stream);
+                            let _ = with_timeout(config.write_timeout, close).await;
+                        }
+                        if let Ok(msg) = &msg {
+                            stats_clone.add_received(msg);
+                        }
                         let should_close = msg.is_err();
                         queue_clone.lock().await.push_back(msg);
+                        notify_received();
                         if should_close {
                             break;
                         }
                     }
                     NextStep::Write(cmd) => {
-                        let should_close = if let Cmd::Send(msg) = cmd {
-                            let res = write_message_to(msg, &mut stream).await;
-                            res.is_err()
-                        } else {
-                            let _ = close_connection(&mut stream).await;
-                            true
+                        let (res, should_close) = match cmd {
+                            Cmd::Send(msg) => {
+                                // Pings and Pongs queued meanwhile go out between
+                                // the fragments until the first other command,
+                                // which has to wait for the message to be sent
+                                let interleave = || {
+                                    if held_back.is_some() {
+                                        return None;
+                                    }
+                                    match rx.try_recv() {
+                                        Ok(Cmd::Send(control)) if control.is_control() => {
+                                            stats_clone.add_sent(&control);
+                                            Some(control)
+                                        }
+                                        Ok(cmd) => {
+                                            held_back = Some(cmd);
+                                            None
+                                        }
+                                        Err(_) => None,
+                                    }
+                                };
+                                let write = write_message_to(&msg, &mut stream, interleave);
+                                let res = with_timeout(config.write_timeout, write).await;
+                                match res {
+                                    Some(Ok(())) => stats_clone.add_sent(&msg),
+                                    Some(Err(error)) => {
+                                        debug!(error, "write failed.");
+                                        let error = Err(MessageError::Network);
+                                        queue_clone.lock().await.push_back(error);
+                                        notify_received();
+                                    }
+                                    None => {}
+                                }
+                                let should_close = !matches!(res, Some(Ok(())));
+                                (res, should_close)
+                            }
+                            Cmd::Close(status) => {
+                                let close = close_connection(&mut stream, status);
+                                (with_timeout(config.write_timeout, close).await, true)
+                            }
                         };
+                        if res.is_none() {
+                            debug!("write timed out.");
+                            queue_clone
+                                .lock()
+                                .await
+                                .push_back(Err(MessageError::Network));
+                            notify_received();
+                        }
                         if should_close {
                             break;
                         }
                     }
                 }
             }
-        });
+            is_closed_clone.store(true, Ordering::Release);
+            notify_received();
+        };
+        let stream_task = task::spawn(stream_loop.instrument(span));
         Self {
-            stream_task,
-            cmd_channel,
-            recv_queue: queue,
+            reader: WebSocketReader {
+                recv_queue: queue,
+                received,
+                drained,
+                is_closed,
+                stats: Arc::clone(&stats),
+            },
+            writer: WebSocketWriter {
+                stream_task,
+                cmd_channel,
+                stats,
+            },
+            protocol,
+            extensions,
+            peer_addr,
         }
     }
 
+    /// Splits the websocket into a half for receiving and a half for sending,
+    /// so both can be moved into different tasks. The stream task keeps
+    /// running until the writer closes it or the connection ends.
+    pub fn split(self) -> (WebSocketReader, WebSocketWriter) {
+        (self.reader, self.writer)
+    }
+
+    /// Returns the address of the peer, if it was given in the [Config].
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns the subprotocol negotiated during the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Returns the extensions negotiated during the handshake.
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    /// Returns whether the stream task has ended, e.g. after a close or a
+    /// network error. Messages received before that may still be queued.
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_closed()
+    }
+
+    /// Returns how many messages and bytes were sent and received so far.
+    pub fn stats(&self) -> ConnectionStats {
+        self.reader.stats()
+    }
+
+    /// Returns how long ago the websocket was created.
+    pub fn connected_duration(&self) -> Duration {
+        self.reader.connected_duration()
+    }
+
     pub async fn shutdown(self) -> Result<(), &'static str> {
+        self.writer.close().await
+    }
+
+    /// Queues a Close frame with the status `code` and `reason`, after which
+    /// the stream task ends. Messages queued before are still sent. Unlike
+    /// [WebSocket::shutdown], this doesn't wait for the frame to be written.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), &'static str> {
+        self.writer.send_close(code, reason).await
+    }
+
+    /// Like [WebSocket::close], but fails right away instead of waiting if the
+    /// queue of messages waiting to be written is full.
+    pub fn try_close(&self, code: u16, reason: &str) -> Result<(), &'static str> {
+        self.writer.try_send_close(code, reason)
+    }
+
+    /// Returns the next read message if it exists. This function does not wait for a new message.
+    pub async fn poll_next_message(&self) -> Option<Result<Message, MessageError>> {
+        self.reader.poll_next_message().await
+    }
+
+    /// Waits for the next read message. Returns `None` once the stream task
+    /// has ended and all messages read before have been returned.
+    pub async fn next_message(&self) -> Option<Result<Message, MessageError>> {
+        self.reader.next_message().await
+    }
+
+    pub async fn try_send(&self, msg: Message) -> Result<(), Message> {
+        self.writer.try_send(msg).await
+    }
+
+    /// Sends `text` in a Text message, see [WebSocket::try_send].
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<(), Message> {
+        self.writer.send_text(text).await
+    }
+
+    /// Sends `bytes` in a Text message after checking they are valid UTF-8,
+    /// as peers must fail the connection on invalid text.
+    pub async fn send_text_bytes(&self, bytes: impl Into<Vec<u8>>) -> Result<(), SendTextError> {
+        self.writer.send_text_bytes(bytes).await
+    }
+
+    /// Sends `data` in a Binary message, see [WebSocket::try_send].
+    pub async fn send_binary(&self, data: impl Into<Bytes>) -> Result<(), Message> {
+        self.writer.send_binary(data).await
+    }
+
+    /// Like [WebSocket::try_send], but takes a shared message, so the same
+    /// message can be sent to many sockets without copying it for each.
+    pub async fn send_shared(&self, msg: Arc<Message>) -> Result<(), Arc<Message>> {
+        self.writer.send_shared(msg).await
+    }
+
+    /// Returns the number of messages queued for the stream task that it
+    /// hasn't started writing yet.
+    pub fn pending_sends(&self) -> usize {
+        self.writer.pending_sends()
+    }
+
+    /// Returns whether the queue of messages waiting to be written is full, so
+    /// [WebSocket::try_send_now] fails and [WebSocket::send_shared] waits.
+    pub fn is_send_queue_full(&self) -> bool {
+        self.writer.is_send_queue_full()
+    }
+
+    /// Like [WebSocket::send_shared], but fails right away instead of waiting
+    /// if the queue of messages waiting to be written is full.
+    pub fn try_send_now(&self, msg: Arc<Message>) -> Result<(), TrySendError> {
+        self.writer.try_send_now(msg)
+    }
+
+    /// Queues a Ping frame. Control frames can carry at most 125 bytes.
+    pub async fn send_ping(&self, payload: Vec<u8>) -> Result<(), &'static str> {
+        self.writer.send_ping(payload).await
+    }
+}
+
+impl Drop for WebSocketWriter {
+    /// Lets the stream task close the connection if it is still running. If
+    /// the Close can't be queued because the peer doesn't read, the task is
+    /// aborted instead, which drops the connection without a Close frame.
+    fn drop(&mut self) {
+        if self.stream_task.is_finished() {
+            return;
+        }
+        if self.cmd_channel.try_send(Cmd::Close(None)).is_err() {
+            debug!("aborting stream task of dropped websocket.");
+            self.stream_task.abort();
+        }
+    }
+}
+
+impl WebSocketReader {
+    /// See [WebSocket::is_closed].
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Acquire)
+    }
+
+    /// See [WebSocket::stats].
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    /// See [WebSocket::connected_duration].
+    pub fn connected_duration(&self) -> Duration {
+        self.stats.connected_at.elapsed()
+    }
+
+    /// See [WebSocket::poll_next_message].
+    pub async fn poll_next_message(&self) -> Option<Result<Message, MessageError>> {
+        let msg = self.recv_queue.lock().await.pop_front();
+        if msg.is_some() {
+            self.drained.notify_one();
+        }
+        msg
+    }
+
+    /// See [WebSocket::next_message].
+    pub async fn next_message(&self) -> Option<Result<Message, MessageError>> {
+        loop {
+            // a notification between the checks and waiting is kept, so it
+            // can't be missed
+            let is_closed = self.is_closed();
+            if let Some(msg) = self.poll_next_message().await {
+                return Some(msg);
+            }
+            if is_closed {
+                return None;
+            }
+            self.received.notified().await;
+        }
+    }
+}
+
+impl WebSocketWriter {
+    /// Closes the connection and waits for the stream task to end, see
+    /// [WebSocket::shutdown].
+    pub async fn close(mut self) -> Result<(), &'static str> {
         self.cmd_channel
-            .send(Cmd::Close)
+            .send(Cmd::Close(None))
             .await
             .map_err(|_| "error sending close command to task")?;
-        self.stream_task
+        (&mut self.stream_task)
             .await
             .map_err(|_| "error waiting on task to end")
     }
 
-    /// Returns the next read message if it exists. This function does not wait for a new message.
-    pub async fn poll_next_message(&self) -> Option<Result<Message, MessageError>> {
-        let mut lock = self.recv_queue.lock().await;
-        lock.pop_front()
+    /// See [WebSocket::stats].
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    /// See [WebSocket::connected_duration].
+    pub fn connected_duration(&self) -> Duration {
+        self.stats.connected_at.elapsed()
     }
 
+    /// See [WebSocket::close].
+    pub async fn send_close(&self, code: u16, reason: &str) -> Result<(), &'static str> {
+        self.cmd_channel
+            .send(Cmd::Close(Some((code, reason.to_owned()))))
+            .await
+            .map_err(|_| "error sending close command to task")
+    }
+
+    /// See [WebSocket::try_send].
     pub async fn try_send(&self, msg: Message) -> Result<(), Message> {
+        self.send_shared(Arc::new(msg))
+            .await
+            .map_err(|msg| Arc::try_unwrap(msg).unwrap_or_else(|msg| (*msg).clone()))
+    }
+
+    /// See [WebSocket::send_text].
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<(), Message> {
+        self.try_send(Message::Text(text.into())).await
+    }
+
+    /// See [WebSocket::send_text_bytes].
+    pub async fn send_text_bytes(&self, bytes: impl Into<Vec<u8>>) -> Result<(), SendTextError> {
+        let text = String::from_utf8(bytes.into()).map_err(SendTextError::InvalidUtf8)?;
+        self.send_text(text).await.map_err(SendTextError::Closed)
+    }
+
+    /// See [WebSocket::send_binary].
+    pub async fn send_binary(&self, data: impl Into<Bytes>) -> Result<(), Message> {
+        self.try_send(Message::Binary(data.into())).await
+    }
+
+    /// See [WebSocket::send_shared].
+    pub async fn send_shared(&self, msg: Arc<Message>) -> Result<(), Arc<Message>> {
         self.cmd_channel
             .send(Cmd::Send(msg))
             .await
             .map_err(|e| e.0.message().unwrap())
     }
+
+    /// See [WebSocket::try_close].
+    pub fn try_send_close(&self, code: u16, reason: &str) -> Result<(), &'static str> {
+        self.cmd_channel
+            .try_send(Cmd::Close(Some((code, reason.to_owned()))))
+            .map_err(|_| "error sending close command to task")
+    }
+
+    /// See [WebSocket::pending_sends].
+    pub fn pending_sends(&self) -> usize {
+        WebSocket::CMD_CHANNEL_BUF_SIZE - self.cmd_channel.capacity()
+    }
+
+    /// See [WebSocket::is_send_queue_full].
+    pub fn is_send_queue_full(&self) -> bool {
+        self.cmd_channel.capacity() == 0
+    }
+
+    /// See [WebSocket::try_send_now].
+    pub fn try_send_now(&self, msg: Arc<Message>) -> Result<(), TrySendError> {
+        self.cmd_channel
+            .try_send(Cmd::Send(msg))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(cmd) => TrySendError::Full(cmd.message().unwrap()),
+                mpsc::error::TrySendError::Closed(cmd) => {
+                    TrySendError::Closed(cmd.message().unwrap())
+                }
+            })
+    }
+
+    /// See [WebSocket::send_ping].
+    pub async fn send_ping(&self, payload: Vec<u8>) -> Result<(), &'static str> {
+        if payload.len() > MAX_CONTROL_PAYLOAD_SIZE {
+            return Err("ping payload is larger than 125 bytes");
+        }
+        self.cmd_channel
+            .send(Cmd::Send(Arc::new(Message::Ping(payload))))
+            .await
+            .map_err(|_| "error sending ping command to task")
+    }
+}
+
+/// Reads the next message and, if the peer sent something invalid, tells it
+/// why the connection fails with a Close frame. Returns `None` if only a
+/// control frame that isn't delivered was read, e.g. a Pong.
+async fn read_message_from<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    config: &Config,
+) -> Result<Option<Message>, MessageError> {
+    let result = read_frames_from(stream, config).await;
+    if let Err(error @ (MessageError::InvalidMessage | MessageError::TooBig)) = &result {
+        let close = Frame::builder()
+            .with_close(error.close_code(), "")
+            .write_to(stream)
+            .await;
+        if let Err(error) = close {
+            debug!(error, "error sending close frame.");
+        }
+    }
+    result
 }
 
-async fn read_message_from(stream: &mut TcpStream) -> Result<Message, MessageError> {
-    let mut message = Vec::new();
+async fn read_frames_from<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    config: &Config,
+) -> Result<Option<Message>, MessageError> {
+    let mut message = BytesMut::new();
+    let mut control = BytesMut::new();
     let mut is_text = None;
+    let mut fragments = 0;
 
     loop {
-        let mut frame = Frame::try_parse_from(stream)
+        let header = Frame::parse_header(stream)
             .await
-            .map_err(|_| MessageError::InvalidMessage)?;
-
-        if is_text.is_none() {
-            is_text = Some(matches!(frame.opcode(), OpCode::Text));
+            .map_err(|error| match error {
+                // the peer went away without a Close frame
+                frame::CONNECTION_CLOSED => MessageError::ConnectionClosed(None),
+                _ => MessageError::InvalidMessage,
+            })?;
+        // no extension that would define reserved opcodes is negotiated
+        if matches!(
+            header.opcode(),
+            OpCode::NonControlReserved(_) | OpCode::ControlReserved(_)
+        ) {
+            debug!(opcode = %header.opcode(), "reserved opcode without an extension.");
+            return Err(MessageError::InvalidMessage);
+        }
+        // and without any extension, nothing may set the reserved bits
+        if header.rsv() != 0 && config.extensions.is_empty() {
+            debug!(
+                rsv = header.rsv(),
+                "reserved bits set without an extension."
+            );
+            return Err(MessageError::InvalidMessage);
+        }
+        // control frames must not be fragmented themselves
+        if header.opcode().is_control() && !header.is_final() {
+            debug!(opcode = %header.opcode(), "fragmented control frame.");
+            return Err(MessageError::InvalidMessage);
         }
 
-        if let Some(mask) = frame.mask() {
-            frame::demask(frame.payload_mut(), mask);
+        // control frames within a fragmented message are not delivered, as
+        // that would drop the fragments read so far
+        let is_message_open = is_text.is_some();
+        let deliver_control = config.deliver_control_messages && !is_message_open;
+        // a message starts with a text or binary frame and continues with
+        // continuation frames until the final one
+        match header.opcode() {
+            OpCode::Continuation if !is_message_open => return Err(MessageError::InvalidMessage),
+            OpCode::Text | OpCode::Binary if is_message_open => {
+                return Err(MessageError::InvalidMessage)
+            }
+            OpCode::Text => is_text = Some(true),
+            OpCode::Binary => is_text = Some(false),
+            _ => {}
         }
 
-        if frame.opcode().is_non_control() {
-            message.extend_from_slice(frame.payload());
+        // data payloads are read straight into the message, control payloads
+        // into their own reused buffer
+        let buf = if header.opcode().is_non_control() {
+            fragments += 1;
+            let too_big =
+                message.len() as u64 + header.payload_len() > config.max_message_size as u64;
+            if too_big || fragments > config.max_fragments {
+                return Err(MessageError::TooBig);
+            }
+            &mut message
+        } else {
+            control.clear();
+            &mut control
+        };
+        let start = buf.len();
+        Frame::parse_body(stream, &header, buf)
+            .await
+            .map_err(|_| MessageError::InvalidMessage)?;
+        if let Some(mask) = header.mask() {
+            frame::demask(&mut buf[start..], mask);
         }
+        let payload = &buf[start..];
 
-        if matches!(frame.opcode(), OpCode::Close) {
-            Frame::builder()
+        if matches!(header.opcode(), OpCode::Close) {
+            // a close payload is either empty or starts with a valid status code
+            let code = payload
+                .get(..2)
+                .map(|code| CloseCode::try_from(u16::from_be_bytes([code[0], code[1]])));
+            if matches!(code, Some(Err(_))) || payload.len() == 1 {
+                debug!(?payload, "invalid close code.");
+                return Err(MessageError::InvalidMessage);
+            }
+            let code = code.and_then(Result::ok).map(u16::from);
+            // the connection is closing whether or not the echo gets through
+            let echo = Frame::builder()
                 .is_final()
                 .with_opcode(OpCode::Close)
-                .with_payload(frame.payload().to_owned())
-                .write_to(stream)
-                .await
-                .map_err(|_| MessageError::Network)?;
-            return Err(MessageError::ConnectionClosed);
-        } else if matches!(frame.opcode(), OpCode::Ping) {
+                .write_payload_to(payload, stream)
+                .await;
+            if let Err(error) = echo {
+                debug!(error, "error echoing close frame.");
+            }
+            return Err(MessageError::ConnectionClosed(code));
+        } else if matches!(header.opcode(), OpCode::Ping) {
             Frame::builder()
                 .is_final()
                 .with_opcode(OpCode::Pong)
-                .with_payload(frame.payload().to_owned())
-                .write_to(stream)
+                .write_payload_to(payload, stream)
                 .await
                 .map_err(|_| MessageError::Network)?;
+            if deliver_control {
+                return Ok(Some(Message::Ping(payload.to_owned())));
+            }
+        } else if matches!(header.opcode(), OpCode::Pong) && deliver_control {
+            return Ok(Some(Message::Pong(payload.to_owned())));
         }
 
-        if frame.is_final() {
+        // only the final data frame ends a message, a control frame on its
+        // own has been answered and isn't a message at all
+        if header.is_final() && header.opcode().is_non_control() {
             break;
+        } else if header.opcode().is_control() && !is_message_open {
+            return Ok(None);
         }
     }
 
     if let Some(true) = is_text {
-        Ok(Message::Text(
-            String::from_utf8_lossy(message.as_slice()).to_string(),
-        ))
+        let text = String::from_utf8(Vec::from(message))
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+        Ok(Some(Message::Text(text)))
     } else {
-        Ok(Message::Binary(message))
+        Ok(Some(Message::Binary(message.freeze())))
     }
 }
 
-async fn write_message_to(message: Message, stream: &mut TcpStream) -> Result<(), &'static str> {
+/// Writes `message` in fragments of 1024 bytes. Before each fragment after
+/// the first, `interleave` is called and the control message it returns is
+/// written first, until it returns `None`.
+async fn write_message_to<S, F>(
+    message: &Message,
+    stream: &mut S,
+    mut interleave: F,
+) -> Result<(), &'static str>
+where
+    S: AsyncWriteExt + Unpin,
+    F: FnMut() -> Option<Arc<Message>>,
+{
     let (first_opcode, bytes) = match message {
-        Message::Text(text) => (OpCode::Text, text.into_bytes()),
-        Message::Binary(bytes) => (OpCode::Binary, bytes),
+        Message::Text(text) => (OpCode::Text, text.as_bytes()),
+        Message::Binary(bytes) => (OpCode::Binary, bytes.as_ref()),
+        Message::Ping(payload) => return write_control_to(OpCode::Ping, payload, stream).await,
+        Message::Pong(payload) => return write_control_to(OpCode::Pong, payload, stream).await,
     };
 
-    if bytes.len() == 0 {
+    if bytes.is_empty() {
         return Ok(());
     }
 
-    let chunks = bytes.chunks(1024).enumerate().collect::<Vec<_>>();
-    let num_chunks = chunks.len();
+    let num_chunks = bytes.len().div_ceil(1024);
 
-    for (idx, chunk) in chunks {
+    for (idx, chunk) in bytes.chunks(1024).enumerate() {
         let mut builder = Frame::builder();
         if idx == num_chunks - 1 {
             builder.is_final();
@@ -205,58 +879,1405 @@ async fn write_message_to(message: Message, stream: &mut TcpStream) -> Result<()
         } else {
             builder.with_opcode(OpCode::Continuation);
         }
-        builder
-            .with_payload(chunk.to_owned())
-            .write_to(stream)
-            .await?;
+        let mut result = Ok(());
+        while idx > 0 && result.is_ok() {
+            let control = if let Some(control) = interleave() {
+                control
+            } else {
+                break;
+            };
+            let (opcode, payload) = match &*control {
+                Message::Ping(payload) => (OpCode::Ping, payload),
+                Message::Pong(payload) => (OpCode::Pong, payload),
+                _ => unreachable!("only control messages are interleaved"),
+            };
+            result = write_control_to(opcode, payload, stream).await;
+        }
+        if result.is_ok() {
+            result = builder.write_payload_to(chunk, stream).await;
+        }
+        if let Err(error) = result {
+            if idx > 0 {
+                // the peer already has the start of the message and would
+                // wait for the rest forever, so try to tell it we gave up
+                let _ = Frame::builder()
+                    .with_close(CLOSE_INTERNAL_ERROR, "message incomplete")
+                    .write_to(stream)
+                    .await;
+            }
+            return Err(error);
+        }
     }
 
     Ok(())
 }
 
-async fn close_connection(stream: &mut TcpStream) -> Result<(), &'static str> {
+/// Awaits `future` for at most `limit`. Returns `None` if it took longer.
+async fn with_timeout<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+async fn close_connection<S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    status: Option<(u16, String)>,
+) -> Result<(), &'static str> {
+    match status {
+        Some((code, reason)) => {
+            Frame::builder()
+                .with_close(code, &reason)
+                .write_to(stream)
+                .await
+        }
+        None => write_control_to(OpCode::Close, &[], stream).await,
+    }
+}
+
+async fn write_control_to<S: AsyncWriteExt + Unpin>(
+    opcode: OpCode,
+    payload: &[u8],
+    stream: &mut S,
+) -> Result<(), &'static str> {
     Frame::builder()
         .is_final()
-        .with_opcode(OpCode::Close)
-        .with_payload(Vec::new())
-        .write_to(stream)
+        .with_opcode(opcode)
+        .write_payload_to(payload, stream)
         .await
 }
 
 impl<S, C> NextStepFuture<S, C> {
-    pub fn new(stream: S, channel: C) -> Self {
-        Self { stream, channel }
+    pub fn new(stream: S, channel: C, read_first: bool) -> Self {
+        Self {
+            stream,
+            channel,
+            read_first,
+        }
     }
 }
 
-impl<S, C> Future for NextStepFuture<S, C>
+impl<S, C, T> Future for NextStepFuture<S, C>
 where
-    S: Future<Output = std::io::Result<usize>>,
+    S: Future<Output = std::io::Result<T>>,
     C: Future<Output = Option<Cmd>>,
 {
     type Output = NextStep;
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.stream.poll(ctx) {
-            Poll::Ready(_) => return Poll::Ready(NextStep::Read),
-            _ => {}
-        };
-        match this.channel.poll(ctx) {
-            Poll::Ready(cmd) => return Poll::Ready(NextStep::Write(cmd.unwrap())),
-            _ => return Poll::Pending,
+        let mut this = self.project();
+        if *this.read_first && this.stream.as_mut().poll(ctx).is_ready() {
+            return Poll::Ready(NextStep::Read);
+        }
+        if let Poll::Ready(cmd) = this.channel.poll(ctx) {
+            // the channel only ends once the writer is gone, which queues a
+            // close before if it can
+            return Poll::Ready(NextStep::Write(cmd.unwrap_or(Cmd::Close(None))));
+        }
+        if !*this.read_first && this.stream.poll(ctx).is_ready() {
+            return Poll::Ready(NextStep::Read);
+        }
+        Poll::Pending
+    }
+}
+
+impl Message {
+    /// Returns the length of the payload in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Text(text) => text.len(),
+            Self::Binary(data) => data.len(),
+            Self::Ping(data) | Self::Pong(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether this is a Ping or Pong, which may be sent in between
+    /// the fragments of another message.
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Ping(_) | Self::Pong(_))
+    }
+}
+
+impl StatsCounters {
+    fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+
+    fn add_sent(&self, msg: &Message) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(msg.len() as u64, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, msg: &Message) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(msg.len() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            connected_at: self.connected_at,
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1 << 20,
+            max_fragments: 1024,
+            max_queued_messages: 64,
+            protocol: None,
+            extensions: Vec::new(),
+            peer_addr: None,
+            span: None,
+            received_notify: None,
+            deliver_control_messages: false,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(10)),
+            idle_timeout: None,
         }
     }
 }
 
 impl Cmd {
-    pub fn message(self) -> Option<Message> {
+    pub fn message(self) -> Option<Arc<Message>> {
         match self {
             Self::Send(m) => Some(m),
-            Self::Close => None,
+            Self::Close(_) => None,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn test_message_len() {
+        assert_eq!(Message::Text(String::from("dit")).len(), 3);
+        assert_eq!(Message::Text(String::from("ä")).len(), 2);
+        assert_eq!(Message::Binary(Bytes::from_static(b"ab")).len(), 2);
+        assert!(Message::Ping(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_protocol() {
+        let (server, _client) = tcp_pair().await;
+        let config = Config {
+            protocol: Some(String::from("morse")),
+            ..Config::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+        assert_eq!(socket.protocol(), Some("morse"));
+
+        let (server, _client) = tcp_pair().await;
+        assert_eq!(WebSocket::new(server).protocol(), None);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_extensions() {
+        let (server, _client) = tcp_pair().await;
+        let config = Config {
+            extensions: vec![String::from("permessage-deflate")],
+            ..Config::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+        assert_eq!(socket.extensions(), ["permessage-deflate"]);
+
+        let (server, _client) = tcp_pair().await;
+        assert!(WebSocket::new(server).extensions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_bits_without_extension_rejected() {
+        // a final text frame with RSV1 set
+        let frame = [0xc1, 0x02, b'h', b'i'];
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&frame).await.unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::InvalidMessage)));
+
+        // a negotiated extension owns the reserved bits
+        let config = Config {
+            extensions: vec![String::from("x-test")],
+            ..Config::default()
+        };
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&frame).await.unwrap();
+        let result = read_message_from(&mut server, &config).await;
+        assert!(matches!(result, Ok(Some(Message::Text(text))) if text == "hi"));
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message_too_big() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let config = Config {
+            max_message_size: 10,
+            ..Config::default()
+        };
+        let fragments = [
+            (OpCode::Text, false),
+            (OpCode::Continuation, false),
+            (OpCode::Continuation, true),
+        ];
+        for (opcode, is_final) in fragments {
+            let mut builder = Frame::builder();
+            if is_final {
+                builder.is_final();
+            } else {
+                builder.is_not_final();
+            }
+            builder
+                .with_opcode(opcode)
+                .with_payload(b"abcd")
+                .write_to(&mut client)
+                .await
+                .unwrap();
+        }
+
+        let result = read_message_from(&mut server, &config).await;
+        assert!(matches!(result, Err(MessageError::TooBig)));
+
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(close.payload(), &1009u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message_within_limit() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let config = Config {
+            max_message_size: 8,
+            ..Config::default()
+        };
+        Frame::builder()
+            .is_not_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"abcd")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Continuation)
+            .with_payload(b"efgh")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        let result = read_message_from(&mut server, &config).await;
+        assert!(matches!(result, Ok(Some(Message::Text(text))) if text == "abcdefgh"));
+    }
+
+    #[tokio::test]
+    async fn test_new_message_within_fragmented_message_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_not_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"abcd")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"efgh")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::InvalidMessage)));
+    }
+
+    #[tokio::test]
+    async fn test_continuation_without_message_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Continuation)
+            .with_payload(b"abcd")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::InvalidMessage)));
+    }
+
+    #[tokio::test]
+    async fn test_ping_within_fragmented_message() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let frames = [
+            (OpCode::Binary, false, &b"ab"[..]),
+            (OpCode::Ping, true, b"p"),
+            (OpCode::Continuation, true, b"cd"),
+        ];
+        for (opcode, is_final, payload) in frames {
+            let mut builder = Frame::builder();
+            if is_final {
+                builder.is_final();
+            } else {
+                builder.is_not_final();
+            }
+            builder
+                .with_opcode(opcode)
+                .with_payload(payload)
+                .write_to(&mut client)
+                .await
+                .unwrap();
+        }
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Ok(Some(Message::Binary(data))) if data == "abcd"));
+    }
+
+    #[tokio::test]
+    async fn test_try_send_now_full_queue() {
+        // the peer never reads, so the stream task blocks on its first write
+        let (server, _client) = tokio::io::duplex(16);
+        let socket = WebSocket::new(server);
+        let message = Arc::new(Message::Text("a".repeat(64)));
+        let result = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Err(e) = socket.try_send_now(Arc::clone(&message)) {
+                    break e;
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+        assert!(matches!(result.await, Ok(TrySendError::Full(_))));
+        assert_eq!(socket.pending_sends(), WebSocket::CMD_CHANNEL_BUF_SIZE);
+        assert!(socket.is_send_queue_full());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_opcodes_rejected() {
+        for opcode in [
+            OpCode::NonControlReserved(0x3),
+            OpCode::ControlReserved(0xb),
+        ] {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            Frame::builder()
+                .is_final()
+                .with_opcode(opcode)
+                .with_payload(b"abcd")
+                .write_to(&mut client)
+                .await
+                .unwrap();
+            let result = read_message_from(&mut server, &Config::default()).await;
+            assert!(
+                matches!(result, Err(MessageError::InvalidMessage)),
+                "{:?}",
+                opcode
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_control_frames_rejected() {
+        for opcode in [OpCode::Close, OpCode::Ping, OpCode::Pong] {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            Frame::builder()
+                .is_not_final()
+                .with_opcode(opcode)
+                .with_payload(b"")
+                .write_to(&mut client)
+                .await
+                .unwrap();
+            let config = Config {
+                deliver_control_messages: true,
+                ..Config::default()
+            };
+            let result = read_message_from(&mut server, &config).await;
+            assert!(
+                matches!(result, Err(MessageError::InvalidMessage)),
+                "{:?}",
+                opcode
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserved_opcode_within_fragmented_message_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_not_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"abcd")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::NonControlReserved(0x4))
+            .with_payload(b"efgh")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::InvalidMessage)));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_fragments() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let config = Config {
+            max_fragments: 4,
+            ..Config::default()
+        };
+        Frame::builder()
+            .is_not_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            Frame::builder()
+                .is_not_final()
+                .with_opcode(OpCode::Continuation)
+                .with_payload(b"")
+                .write_to(&mut client)
+                .await
+                .unwrap();
+        }
+
+        let result = read_message_from(&mut server, &config).await;
+        assert!(matches!(result, Err(MessageError::TooBig)));
+
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(close.payload(), &1009u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_drop_closes_connection() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        socket.send_text("last").await.unwrap();
+        drop(socket);
+
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.payload(), b"last");
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        // the stream task ended, which dropped its end of the pipe
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut rest));
+        assert!(matches!(read.await, Ok(Ok(0))));
+    }
+
+    #[tokio::test]
+    async fn test_drop_aborts_stuck_stream_task() {
+        // the peer never reads, so the close can't be queued
+        let (mut client, server) = tokio::io::duplex(16);
+        let socket = WebSocket::new(server);
+        let message = Arc::new(Message::Text("a".repeat(64)));
+        while socket.try_send_now(Arc::clone(&message)).is_ok() {
+            task::yield_now().await;
+        }
+        drop(socket);
+
+        // writing fails once the aborted task dropped its end of the pipe
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while client.write_all(b"x").await.is_ok() {
+                task::yield_now().await;
+            }
+        });
+        assert!(closed.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_full_recv_queue_stops_reading() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = Config {
+            max_queued_messages: 2,
+            ..Config::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+        for text in ["1", "2", "3", "4"] {
+            Frame::builder()
+                .is_final()
+                .with_opcode(OpCode::Text)
+                .with_payload(text)
+                .write_to(&mut client)
+                .await
+                .unwrap();
+        }
+        let queued = || async { socket.reader.recv_queue.lock().await.len() };
+        for _ in 0..20 {
+            task::yield_now().await;
+        }
+        assert_eq!(queued().await, 2);
+
+        let received = socket.poll_next_message().await;
+        assert!(matches!(received, Some(Ok(Message::Text(text))) if text == "1"));
+        for _ in 0..20 {
+            task::yield_now().await;
+        }
+        assert_eq!(queued().await, 2);
+        for expected in ["2", "3", "4"] {
+            let received = socket.next_message().await;
+            assert!(matches!(received, Some(Ok(Message::Text(text))) if text == expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_notify_shared_by_sockets() {
+        let notify = Arc::new(Notify::new());
+        let config = Config {
+            received_notify: Some(Arc::clone(&notify)),
+            ..Config::default()
+        };
+        let (_first_client, first) = tokio::io::duplex(1024);
+        let (mut second_client, second) = tokio::io::duplex(1024);
+        let _first = WebSocket::with_config(first, config.clone());
+        let second = WebSocket::with_config(second, config);
+
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"hi")
+            .write_to(&mut second_client)
+            .await
+            .unwrap();
+        let notified = tokio::time::timeout(Duration::from_secs(1), notify.notified()).await;
+        assert!(notified.is_ok());
+        let received = second.poll_next_message().await;
+        assert!(matches!(received, Some(Ok(Message::Text(text))) if text == "hi"));
+    }
+
+    #[tokio::test]
+    async fn test_send_and_receive_over_duplex() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"ping")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let received = loop {
+            match socket.poll_next_message().await {
+                Some(msg) => break msg,
+                None => task::yield_now().await,
+            }
+        };
+        assert!(matches!(received, Ok(Message::Text(text)) if text == "ping"));
+
+        socket
+            .try_send(Message::Text(String::from("pong")))
+            .await
+            .unwrap();
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_messages() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        let stats = socket.stats();
+        assert_eq!((stats.messages_sent, stats.messages_received), (0, 0));
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (0, 0));
+
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"hello")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let received = socket.next_message().await;
+        assert!(matches!(received, Some(Ok(Message::Text(text))) if text == "hello"));
+
+        socket.send_text("hi").await.unwrap();
+        socket.send_binary(vec![0u8; 10]).await.unwrap();
+        for _ in 0..2 {
+            Frame::try_parse_from(&mut client).await.unwrap();
+        }
+        // the counters are updated once a write completed, which may be
+        // after the peer has read the frame
+        let sent = tokio::time::timeout(Duration::from_secs(1), async {
+            while socket.stats().messages_sent < 2 {
+                task::yield_now().await;
+            }
+        })
+        .await;
+        assert!(sent.is_ok());
+
+        let stats = socket.stats();
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.bytes_received, 5);
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!(stats.bytes_sent, 12);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connected_duration() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(socket.connected_duration() >= Duration::from_secs(5));
+
+        let (reader, writer) = socket.split();
+        assert_eq!(reader.stats(), writer.stats());
+    }
+
+    #[tokio::test]
+    async fn test_large_message_through_small_pipe() {
+        // every write only partly fits into the pipe
+        let (mut client, server) = tokio::io::duplex(64);
+        let socket = WebSocket::new(server);
+        let data = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        socket.send_binary(data.clone()).await.unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let frame = Frame::try_parse_from(&mut client).await.unwrap();
+            received.extend_from_slice(frame.payload());
+            if frame.is_final() {
+                break;
+            }
+        }
+        assert_eq!(received, data);
+    }
+
+    /// Sends `data` from a client in masked frames of `frame_len` bytes and
+    /// returns the message the socket reassembled from them.
+    async fn receive_in_frames(data: &[u8], frame_len: usize) -> Message {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let socket = WebSocket::new(server);
+        let frames = data
+            .chunks(frame_len)
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<_>>();
+        let write = task::spawn(async move {
+            let mask = [0x12, 0x34, 0x56, 0x78];
+            let last = frames.len() - 1;
+            for (idx, mut payload) in frames.into_iter().enumerate() {
+                frame::demask(&mut payload, mask);
+                let mut builder = Frame::builder();
+                builder.with_mask(mask);
+                builder.with_opcode(if idx == 0 {
+                    OpCode::Binary
+                } else {
+                    OpCode::Continuation
+                });
+                if idx == last {
+                    builder.is_final();
+                } else {
+                    builder.is_not_final();
+                }
+                builder
+                    .write_payload_to(&payload, &mut client)
+                    .await
+                    .unwrap();
+            }
+            client
+        });
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next_message()).await;
+        write.await.unwrap();
+        message.unwrap().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_large_binary_message_reassembled() {
+        let chunk = 64 * 1024;
+        let cases = [
+            // a multiple of the read chunk size, in frames of exactly that size
+            (3 * chunk, chunk),
+            // neither the message nor the frames line up with the chunks
+            (3 * chunk + 123, 70_000),
+            // a single frame with the 8 byte length form
+            (2 * chunk + 1, 2 * chunk + 1),
+        ];
+        for (len, frame_len) in cases {
+            let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            match receive_in_frames(&data, frame_len).await {
+                Message::Binary(received) => assert!(received == data, "{} in {}", len, frame_len),
+                other => panic!("expected binary message, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_byte_lost_after_readiness_check() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        let mut frame = Vec::new();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"split")
+            .write_to(&mut frame)
+            .await
+            .unwrap();
+
+        // let the stream task see only the first byte before the rest arrives
+        client.write_all(&frame[..1]).await.unwrap();
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+        client.write_all(&frame[1..]).await.unwrap();
+
+        let received = loop {
+            match socket.poll_next_message().await {
+                Some(msg) => break msg,
+                None => task::yield_now().await,
+            }
+        };
+        assert!(matches!(received, Ok(Message::Text(text)) if text == "split"));
+    }
+
+    #[tokio::test]
+    async fn test_close_honored_under_read_pressure() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        let mut frame = Vec::new();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"flood")
+            .write_to(&mut frame)
+            .await
+            .unwrap();
+
+        // keep data available until the server side goes away
+        let flood = task::spawn(async move { while client.write_all(&frame).await.is_ok() {} });
+        task::yield_now().await;
+
+        let shutdown = tokio::time::timeout(Duration::from_secs(1), socket.shutdown()).await;
+        assert!(matches!(shutdown, Ok(Ok(()))));
+        flood.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed_after_peer_close() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        assert!(!socket.is_closed());
+
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Close)
+            .with_payload(1000u16.to_be_bytes())
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while !socket.is_closed() {
+                task::yield_now().await;
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+        assert!(matches!(
+            socket.poll_next_message().await,
+            Some(Err(MessageError::ConnectionClosed(Some(1000))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pong_delivered_when_enabled() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let config = Config {
+            deliver_control_messages: true,
+            ..Config::default()
+        };
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Pong)
+            .with_payload(b"beat")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+
+        let result = read_message_from(&mut server, &config).await;
+        assert!(matches!(result, Ok(Some(Message::Pong(payload))) if payload == b"beat"));
+    }
+
+    #[tokio::test]
+    async fn test_lone_control_frames_are_no_message() {
+        // an empty, masked Pong as browsers send it
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[0x8a, 0x80, 1, 2, 3, 4]).await.unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Ok(None)), "{:?}", result);
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Ping)
+            .with_payload(b"beat")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Ok(None)), "{:?}", result);
+        let pong = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(pong.opcode(), OpCode::Pong);
+
+        // the socket skips them and delivers the next real message
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+        client.write_all(&[0x8a, 0x80, 1, 2, 3, 4]).await.unwrap();
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .with_payload(b"hi")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(1), socket.next_message()).await;
+        assert!(
+            matches!(&message, Ok(Some(Ok(Message::Text(text)))) if text == "hi"),
+            "{:?}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_text_and_binary() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+
+        socket.send_text("hello").await.unwrap();
+        socket.send_text(String::from("wörld")).await.unwrap();
+        socket.send_binary(vec![0u8, 159, 146, 150]).await.unwrap();
+        socket.send_binary(&b"static"[..]).await.unwrap();
+        let expected: [(OpCode, &[u8]); 4] = [
+            (OpCode::Text, b"hello"),
+            (OpCode::Text, "wörld".as_bytes()),
+            (OpCode::Binary, &[0, 159, 146, 150]),
+            (OpCode::Binary, b"static"),
+        ];
+        for (opcode, payload) in expected {
+            let frame = Frame::try_parse_from(&mut client).await.unwrap();
+            assert_eq!(frame.opcode(), opcode);
+            assert_eq!(frame.payload(), payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_text_bytes_validates_utf8() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+
+        let result = socket.send_text_bytes(vec![b'h', b'i', 0xff]).await;
+        assert!(matches!(result, Err(SendTextError::InvalidUtf8(_))));
+        assert_eq!(socket.pending_sends(), 0);
+
+        socket.send_text_bytes("grüß".as_bytes()).await.unwrap();
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), "grüß".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_send_ping() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+
+        socket.send_ping(b"latency".to_vec()).await.unwrap();
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.opcode(), OpCode::Ping);
+        assert!(frame.is_final());
+        assert_eq!(frame.payload(), b"latency");
+
+        assert!(socket.send_ping(vec![0; 126]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_split_halves_in_separate_tasks() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = WebSocket::new(server).split();
+        let reading = task::spawn(async move {
+            let mut texts = Vec::new();
+            while let Some(Ok(Message::Text(text))) = reader.next_message().await {
+                texts.push(text);
+            }
+            texts
+        });
+        let writing = task::spawn(async move {
+            for text in ["a", "b"] {
+                writer
+                    .try_send(Message::Text(text.to_owned()))
+                    .await
+                    .unwrap();
+            }
+            writer
+        });
+
+        for text in ["1", "2", "3"] {
+            Frame::builder()
+                .is_final()
+                .with_opcode(OpCode::Text)
+                .write_payload_to(text.as_bytes(), &mut client)
+                .await
+                .unwrap();
+        }
+        for text in ["a", "b"] {
+            let frame = Frame::try_parse_from(&mut client).await.unwrap();
+            assert_eq!(frame.payload(), text.as_bytes());
+        }
+        Frame::builder()
+            .with_close(1000, "")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let texts = tokio::time::timeout(Duration::from_secs(1), reading)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts, ["1", "2", "3"]);
+        let writer = writing.await.unwrap();
+        assert!(writer
+            .try_send(Message::Text(String::from("c")))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_is_closed() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = Config {
+            idle_timeout: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Text)
+            .write_payload_to(b"hi", &mut client)
+            .await
+            .unwrap();
+        // the message resets the timeout
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert!(!socket.is_closed());
+
+        let start = Instant::now();
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(&close.payload()[..2], &1001u16.to_be_bytes());
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+        assert!(matches!(
+            socket.poll_next_message().await,
+            Some(Ok(Message::Text(_)))
+        ));
+        assert!(matches!(
+            socket.poll_next_message().await,
+            Some(Err(MessageError::Idle))
+        ));
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_message_error_close_codes() {
+        assert_eq!(MessageError::ConnectionClosed(None).close_code(), 1000);
+        assert_eq!(
+            MessageError::ConnectionClosed(Some(4001)).close_code(),
+            4001
+        );
+        assert_eq!(MessageError::InvalidMessage.close_code(), 1002);
+        assert_eq!(MessageError::Network.close_code(), 1011);
+        assert_eq!(MessageError::SendFailed.close_code(), 1011);
+        assert_eq!(MessageError::TooBig.close_code(), 1009);
+        assert_eq!(MessageError::Idle.close_code(), 1001);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error_sends_close() {
+        // a continuation frame without a message to continue
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Continuation)
+            .with_payload(b"abcd")
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::InvalidMessage)));
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(close.payload(), &1002u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_close_codes() {
+        for (code, is_valid) in [(1000, true), (999, false), (1005, false)] {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            Frame::builder()
+                .with_close(code, "")
+                .write_to(&mut client)
+                .await
+                .unwrap();
+            let result = read_message_from(&mut server, &Config::default()).await;
+            let close = Frame::try_parse_from(&mut client).await.unwrap();
+            assert_eq!(close.opcode(), OpCode::Close);
+            if is_valid {
+                assert!(
+                    matches!(result, Err(MessageError::ConnectionClosed(Some(c))) if c == code)
+                );
+                assert_eq!(close.payload(), &code.to_be_bytes());
+            } else {
+                assert!(
+                    matches!(result, Err(MessageError::InvalidMessage)),
+                    "{}",
+                    code
+                );
+                assert_eq!(close.payload(), &1002u16.to_be_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_with_status() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let socket = WebSocket::new(server);
+
+        socket.send_text("bye").await.unwrap();
+        socket.close(1001, "going away").await.unwrap();
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.payload(), b"bye");
+        let close = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(&close.payload()[..2], &1001u16.to_be_bytes());
+        assert_eq!(&close.payload()[2..], b"going away");
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while !socket.is_closed() {
+                task::yield_now().await;
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_after_failed_fragment() {
+        let message = Message::Binary(Bytes::from(vec![7; 3000]));
+        // the first fragment has a 4 byte header and 1024 bytes of payload
+        let mut writer = TestWriter {
+            fail_at: Some(1028),
+            ..TestWriter::default()
+        };
+        assert!(write_message_to(&message, &mut writer, || None)
+            .await
+            .is_err());
+
+        let written = writer.written();
+        let mut written = &written[..];
+        let first = Frame::try_parse_from(&mut written).await.unwrap();
+        assert_eq!(first.opcode(), OpCode::Binary);
+        assert!(!first.is_final());
+        let close = Frame::try_parse_from(&mut written).await.unwrap();
+        assert_eq!(close.opcode(), OpCode::Close);
+        assert_eq!(&close.payload()[..2], &CLOSE_INTERNAL_ERROR.to_be_bytes());
+        assert!(written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ping_between_fragments() {
+        // the client doesn't read yet, so the first fragment doesn't fit
+        let (mut client, server) = tokio::io::duplex(512);
+        let socket = WebSocket::new(server);
+
+        socket.send_binary(vec![7u8; 4096]).await.unwrap();
+        // wait for the stream task to take the message and get stuck on it
+        while socket.pending_sends() > 0 {
+            task::yield_now().await;
+        }
+        socket.send_ping(b"keepalive".to_vec()).await.unwrap();
+        socket.send_text("after").await.unwrap();
+
+        let mut opcodes = Vec::new();
+        for _ in 0..6 {
+            let frame = Frame::try_parse_from(&mut client).await.unwrap();
+            if frame.opcode() == OpCode::Ping {
+                assert_eq!(frame.payload(), b"keepalive");
+            }
+            opcodes.push(frame.opcode());
+        }
+        assert_eq!(
+            opcodes,
+            [
+                OpCode::Binary,
+                OpCode::Ping,
+                OpCode::Continuation,
+                OpCode::Continuation,
+                OpCode::Continuation,
+                OpCode::Text,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragments_keep_send_backpressure() {
+        // the client doesn't read yet, so the first fragment doesn't fit
+        let (mut client, server) = tokio::io::duplex(512);
+        let socket = WebSocket::new(server);
+
+        socket.send_binary(vec![7u8; 4096]).await.unwrap();
+        while socket.pending_sends() > 0 {
+            task::yield_now().await;
+        }
+        for _ in 0..WebSocket::CMD_CHANNEL_BUF_SIZE {
+            let text = Arc::new(Message::Text(String::from("after")));
+            socket.try_send_now(text).unwrap();
+        }
+
+        // before the next fragment, the stream task looks for Pings once
+        let frame = Frame::try_parse_from(&mut client).await.unwrap();
+        assert_eq!(frame.opcode(), OpCode::Binary);
+        while socket.pending_sends() == WebSocket::CMD_CHANNEL_BUF_SIZE {
+            task::yield_now().await;
+        }
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+        // only the one text it came across waits outside of the channel
+        assert_eq!(socket.pending_sends(), WebSocket::CMD_CHANNEL_BUF_SIZE - 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_close_after_failed_first_fragment() {
+        let message = Message::Binary(Bytes::from(vec![7; 3000]));
+        let mut writer = TestWriter {
+            fail_at: Some(0),
+            ..TestWriter::default()
+        };
+        assert!(write_message_to(&message, &mut writer, || None)
+            .await
+            .is_err());
+        // nothing of the message reached the peer, so there's nothing to end
+        assert!(writer.written().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_end_of_stream_is_connection_closed() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(result, Err(MessageError::ConnectionClosed(None))));
+    }
+
+    #[tokio::test]
+    async fn test_close_when_echo_fails() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        Frame::builder()
+            .is_final()
+            .with_opcode(OpCode::Close)
+            .with_payload(1001u16.to_be_bytes())
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        // the close frame stays readable, but writing the echo now fails
+        drop(client);
+
+        let result = read_message_from(&mut server, &Config::default()).await;
+        assert!(matches!(
+            result,
+            Err(MessageError::ConnectionClosed(Some(1001)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_shared_to_many_sockets() {
+        let payload = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let message = Arc::new(Message::Binary(Bytes::from(payload.clone())));
+        let mut clients = Vec::new();
+        let mut sockets = Vec::new();
+        for _ in 0..5 {
+            let (client, server) = tokio::io::duplex(4096);
+            clients.push(client);
+            sockets.push(WebSocket::new(server));
+        }
+
+        for socket in &sockets {
+            socket.send_shared(Arc::clone(&message)).await.unwrap();
+        }
+        for client in &mut clients {
+            let mut received = Vec::new();
+            loop {
+                let frame = Frame::try_parse_from(client).await.unwrap();
+                received.extend_from_slice(frame.payload());
+                if frame.is_final() {
+                    break;
+                }
+            }
+            assert_eq!(received, payload);
+        }
+    }
+
+    async fn next_message(socket: &WebSocket) -> Result<Message, MessageError> {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                match socket.poll_next_message().await {
+                    Some(msg) => break msg,
+                    None => task::yield_now().await,
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stalled_read_times_out() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = Config {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+
+        // the header announces five bytes, but only one follows
+        client.write_all(&[0x81, 0x05, b'h']).await.unwrap();
+        assert!(matches!(
+            next_message(&socket).await,
+            Err(MessageError::Network)
+        ));
+        assert!(socket.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_write_times_out() {
+        // the client never reads, so the small buffer fills up
+        let (_client, server) = tokio::io::duplex(64);
+        let config = Config {
+            write_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+
+        socket
+            .try_send(Message::Binary(Bytes::from(vec![0; 4096])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_message(&socket).await,
+            Err(MessageError::Network)
+        ));
+        assert!(socket.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_peer_addr() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let addr = SocketAddr::from(([192, 0, 2, 1], 4242));
+        let config = Config {
+            peer_addr: Some(addr),
+            ..Default::default()
+        };
+        let socket = WebSocket::with_config(server, config);
+        assert_eq!(socket.peer_addr(), Some(addr));
+
+        let (_client, server) = tokio::io::duplex(1024);
+        assert_eq!(WebSocket::new(server).peer_addr(), None);
+    }
+
+    /// Writer shared by the tests of this crate. It records every write,
+    /// accepts at most `max_write` bytes per call and fails the first write
+    /// after `fail_at` bytes were written. Vectored writes are recorded as
+    /// one write.
+    pub(crate) struct TestWriter {
+        pub(crate) writes: Vec<Vec<u8>>,
+        pub(crate) max_write: usize,
+        pub(crate) fail_at: Option<usize>,
+    }
+
+    impl TestWriter {
+        /// Returns everything written so far.
+        pub(crate) fn written(&self) -> Vec<u8> {
+            self.writes.concat()
+        }
+    }
+
+    impl Default for TestWriter {
+        fn default() -> Self {
+            Self {
+                writes: Vec::new(),
+                max_write: usize::MAX,
+                fail_at: None,
+            }
+        }
+    }
+
+    impl AsyncWrite for TestWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let written: usize = self.writes.iter().map(Vec::len).sum();
+            if self.fail_at.is_some_and(|fail_at| written >= fail_at) {
+                self.fail_at = None;
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            let len = buf.len().min(self.max_write);
+            self.writes.push(buf[..len].to_vec());
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            ctx: &mut Context<'_>,
+            bufs: &[std::io::IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            let buf: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            self.poll_write(ctx, &buf)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
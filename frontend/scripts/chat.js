@@ -3,7 +3,8 @@ const roomName = url.searchParams.get("room");
 if (!roomName) {
     location.href = "/";
 }
-const ws = new WebSocket(`ws://${location.host}/ws?room=${roomName}`);
+const wsProtocol = location.protocol === "https:" ? "wss" : "ws";
+const ws = new WebSocket(`${wsProtocol}://${location.host}/ws?room=${roomName}`);
 let ctx = null;
 let ownOsc = null;
 
@@ -39,7 +40,22 @@ const peerUserStore = {};
 
 function onWebSocketMessage(event) {
     if (typeof event.data !== "string") return;
-    const [message, callsign] = event.data.split(":");
+    if (event.data.startsWith("{")) {
+        onControlMessage(JSON.parse(event.data));
+        return;
+    }
+    showSignal(event.data, true);
+}
+
+function onControlMessage(control) {
+    // the room's recent signals, shown without playing them again
+    if (control.type === "history") {
+        control.messages.forEach(data => showSignal(data, false));
+    }
+}
+
+function showSignal(data, isLive) {
+    const [message, callsign] = data.split(":");
     if (!isValidCallsign(callsign)) {
         console.info(`received message with invalid callsign "${callsign}". ignoring message.`);
         return;
@@ -62,9 +78,9 @@ function onWebSocketMessage(event) {
     clearTimeout(peerUserStore[callsign].flushSignalBufferTimeout);
     peerUserStore[callsign].currentSignalsBuffer.push(message);
     
-    if (message === DIT_SIGNAL) {
+    if (isLive && message === DIT_SIGNAL) {
         playIncomingDit(ctx, userData.ditlen);
-    } else if (message === DAH_SIGNAL) {
+    } else if (isLive && message === DAH_SIGNAL) {
         playIncomingDah(ctx, userData.ditlen);
     }
     
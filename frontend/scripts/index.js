@@ -1,6 +1,6 @@
 async function main() {
   const resp = await fetch("/api/rooms");
-  const rooms = await resp.json();
+  const { rooms } = await resp.json();
   const roomsContainer = document.querySelector("#rooms-container");
   
   for (const room of rooms) {